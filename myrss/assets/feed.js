@@ -10,20 +10,88 @@ function getCookie(name) {
 eventSource.onmessage = function(event) { // console.log("appending message: ");
     // console.log(event.data);
     let parsedData = JSON.parse(event.data);
+    if (parsedData.kind === "edit") {
+        let existing = document.getElementById("message-" + parsedData.id);
+        if (existing) {
+            existing.insertAdjacentHTML("beforebegin", parsedData.message);
+            let updated = existing.previousElementSibling;
+            existing.remove();
+            htmx.process(updated);
+        }
+        return;
+    }
+    if (parsedData.kind === "replies") {
+        let existing = document.getElementById("replies-" + parsedData.id);
+        if (existing) {
+            existing.textContent = parsedData.label;
+        }
+        return;
+    }
+    if (parsedData.kind === "reactions") {
+        let existing = document.getElementById("reactions-" + parsedData.id);
+        if (existing) {
+            existing.insertAdjacentHTML("beforebegin", parsedData.reactions);
+            let updated = existing.previousElementSibling;
+            existing.remove();
+            htmx.process(updated);
+        }
+        return;
+    }
+    if (parsedData.kind === "previews") {
+        let existing = document.getElementById("previews-" + parsedData.id);
+        if (existing) {
+            existing.outerHTML = parsedData.previews;
+        }
+        return;
+    }
+    if (parsedData.kind === "notice") {
+        let messages = document.getElementById("messages");
+        messages.insertAdjacentHTML("afterbegin", parsedData.message);
+        htmx.process(messages.firstElementChild);
+        return;
+    }
+    if (parsedData.kind === "kicked") {
+        // Closed before the server ends the stream, so it isn't reopened
+        eventSource.close();
+        let reason = parsedData.reason ? " Reason: " + parsedData.reason : "";
+        alert("You were removed from the chat." + reason);
+        window.location = "/";
+        return;
+    }
+    if (parsedData.kind === "delete") {
+        let existing = document.getElementById("message-" + parsedData.id);
+        if (existing) {
+            existing.remove();
+        }
+        return;
+    }
     let sender = parsedData.sender;
     let message = parsedData.message;
     let preview = parsedData.preview;
-    let notify = parsedData.notify;
+    let mentioned = parsedData.mentioned;
 
-    document.getElementById("messages").insertAdjacentHTML("afterbegin", message);
+    // Only show messages that were sent in the thread or direct message
+    // conversation that is open, or outside of any on the main feed
+    let messages = document.getElementById("messages");
+    let thread = messages.dataset.thread ? Number(messages.dataset.thread) : null;
+    let dm = messages.dataset.dm ?? null;
+    if ((parsedData.parent ?? null) === thread && (parsedData.dm ?? null) === dm) {
+        messages.insertAdjacentHTML("afterbegin", message);
+        htmx.process(messages.firstElementChild);
+    }
 
     // Check if the browser supports notifications
-    if (sender != getCookie("sender-name") && "Notification" in window && notify) {
+    if (sender != getCookie("sender-name") && "Notification" in window && mentioned) {
         if (Notification.permission === "granted" && !document.hasFocus()) {
             // Create the notification
             var notification = new Notification("Message from " + sender, {
                 body: preview,
             });
+            if (parsedData.dm) {
+                notification.onclick = () => {
+                    window.location = "/dm/" + encodeURIComponent(parsedData.dm);
+                };
+            }
         }
     }
 };
@@ -38,6 +106,12 @@ window.addEventListener("beforeunload", (event) => {
         console.log("SSE connection closed");
     }
 });
+// The id of the message the emoji picker is choosing a reaction for, if any
+let reactionTarget = null;
+function openReactionPicker(id) {
+    reactionTarget = id;
+    document.getElementById("pickerDialog").showModal();
+}
 function togglePickerOpen() {
     let pickerDialog = document.getElementById("pickerDialog");
     if (pickerDialog.open) {
@@ -49,6 +123,15 @@ function togglePickerOpen() {
 document.addEventListener("DOMContentLoaded", () => {
     document.querySelector('emoji-picker')
         .addEventListener('emoji-click', function(event) {
+            if (reactionTarget !== null) {
+                htmx.ajax("POST", "/messages/" + reactionTarget + "/reactions", {
+                    values: { emoji: event.detail.unicode },
+                    swap: "none",
+                });
+                reactionTarget = null;
+                document.getElementById("pickerDialog").close();
+                return;
+            }
             document.getElementById("message-input").value += event.detail.unicode;
             togglePickerOpen();
         });
@@ -57,3 +140,9 @@ document.addEventListener("DOMContentLoaded", () => {
         togglePickerOpen();
     });
 });
+// Say why a message wasn't sent, such as an attachment being too large
+document.addEventListener("htmx:responseError", (event) => {
+    if (event.detail.elt.id === "send-message-form" && event.detail.xhr.responseText) {
+        alert(event.detail.xhr.responseText);
+    }
+});
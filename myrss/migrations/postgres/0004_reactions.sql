@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS reactions (
+  message INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  username TEXT NOT NULL,
+  emoji TEXT NOT NULL,
+  created TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (message, username, emoji)
+);
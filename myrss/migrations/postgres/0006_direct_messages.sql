@@ -0,0 +1,2 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS recipient TEXT;
+CREATE INDEX IF NOT EXISTS messages_recipient ON messages (recipient);
@@ -0,0 +1,3 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS search tsvector
+  GENERATED ALWAYS AS (to_tsvector('english', source)) STORED;
+CREATE INDEX IF NOT EXISTS messages_search ON messages USING GIN (search);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS users (
+  key TEXT PRIMARY KEY,
+  name TEXT NOT NULL,
+  last_seen TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE IF NOT EXISTS bots (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  data TEXT NOT NULL
+);
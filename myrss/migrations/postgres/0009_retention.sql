@@ -0,0 +1,6 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS pinned BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE TABLE IF NOT EXISTS retention_policies (
+  room INTEGER REFERENCES rooms(id) ON DELETE CASCADE,
+  max_age_days INTEGER,
+  max_count INTEGER
+);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS attachments (
+  key TEXT PRIMARY KEY,
+  message INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  filename TEXT NOT NULL,
+  content_type TEXT NOT NULL,
+  size BIGINT NOT NULL,
+  has_thumbnail BOOLEAN NOT NULL DEFAULT FALSE,
+  position SERIAL
+);
+CREATE INDEX IF NOT EXISTS attachments_message ON attachments (message);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS link_previews (
+  url TEXT PRIMARY KEY,
+  title TEXT,
+  description TEXT,
+  site_name TEXT,
+  fetched_at TIMESTAMPTZ NOT NULL
+);
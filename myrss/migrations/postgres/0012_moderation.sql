@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS sanctions (
+  user_key TEXT NOT NULL,
+  kind TEXT NOT NULL,
+  reason TEXT,
+  moderator TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL,
+  expires_at TIMESTAMPTZ,
+  PRIMARY KEY (user_key, kind)
+);
+
+CREATE TABLE IF NOT EXISTS moderation_log (
+  id SERIAL PRIMARY KEY,
+  created_at TIMESTAMPTZ NOT NULL,
+  moderator TEXT NOT NULL,
+  action TEXT NOT NULL,
+  target TEXT NOT NULL,
+  reason TEXT,
+  expires_at TIMESTAMPTZ
+);
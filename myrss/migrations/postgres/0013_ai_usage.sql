@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS ai_usage (
+  day DATE NOT NULL,
+  bot TEXT NOT NULL,
+  user_key TEXT NOT NULL,
+  prompt_tokens BIGINT NOT NULL,
+  completion_tokens BIGINT NOT NULL,
+  requests BIGINT NOT NULL,
+  PRIMARY KEY (day, bot, user_key)
+);
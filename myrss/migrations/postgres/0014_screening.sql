@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS screen_policies (
+  room INTEGER REFERENCES rooms(id) ON DELETE CASCADE,
+  action TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS reviews (
+  id SERIAL PRIMARY KEY,
+  message INTEGER REFERENCES messages(id) ON DELETE CASCADE,
+  action TEXT NOT NULL,
+  reason TEXT NOT NULL,
+  sender TEXT NOT NULL,
+  parent_id INTEGER REFERENCES messages(id) ON DELETE CASCADE,
+  source TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS session TEXT;
+ALTER TABLE reviews ADD COLUMN IF NOT EXISTS session TEXT;
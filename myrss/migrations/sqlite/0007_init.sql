@@ -0,0 +1,53 @@
+-- The schema of the Postgres migrations up to 0007, which came before SQLite
+-- was supported
+
+CREATE TABLE IF NOT EXISTS messages (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  parent_id INTEGER REFERENCES messages(id) ON DELETE CASCADE,
+  sender TEXT NOT NULL DEFAULT '',
+  recipient TEXT,
+  sent_date TEXT NOT NULL,
+  edited_at TEXT,
+  contents TEXT NOT NULL,
+  source TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS messages_parent_id ON messages (parent_id);
+CREATE INDEX IF NOT EXISTS messages_recipient ON messages (recipient);
+
+CREATE TABLE IF NOT EXISTS rooms (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  description TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS room_messages (
+  room INTEGER REFERENCES rooms(id),
+  message INTEGER REFERENCES messages(id)
+);
+
+CREATE TABLE IF NOT EXISTS reactions (
+  message INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  username TEXT NOT NULL,
+  emoji TEXT NOT NULL,
+  created TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+  PRIMARY KEY (message, username, emoji)
+);
+
+-- Full-text index over the Markdown source of messages, kept in sync with
+-- the messages table by the triggers below
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_search USING fts5(
+  source,
+  content = 'messages',
+  content_rowid = 'id',
+  tokenize = 'porter unicode61'
+);
+CREATE TRIGGER IF NOT EXISTS messages_search_insert AFTER INSERT ON messages BEGIN
+  INSERT INTO messages_search (rowid, source) VALUES (new.id, new.source);
+END;
+CREATE TRIGGER IF NOT EXISTS messages_search_delete AFTER DELETE ON messages BEGIN
+  INSERT INTO messages_search (messages_search, rowid, source) VALUES ('delete', old.id, old.source);
+END;
+CREATE TRIGGER IF NOT EXISTS messages_search_update AFTER UPDATE OF source ON messages BEGIN
+  INSERT INTO messages_search (messages_search, rowid, source) VALUES ('delete', old.id, old.source);
+  INSERT INTO messages_search (rowid, source) VALUES (new.id, new.source);
+END;
@@ -0,0 +1,6 @@
+ALTER TABLE messages ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+CREATE TABLE IF NOT EXISTS retention_policies (
+  room INTEGER REFERENCES rooms(id) ON DELETE CASCADE,
+  max_age_days INTEGER,
+  max_count INTEGER
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS attachments (
+  key TEXT PRIMARY KEY,
+  message INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  filename TEXT NOT NULL,
+  content_type TEXT NOT NULL,
+  size INTEGER NOT NULL,
+  has_thumbnail INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS attachments_message ON attachments (message);
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS sanctions (
+  user_key TEXT NOT NULL,
+  kind TEXT NOT NULL,
+  reason TEXT,
+  moderator TEXT NOT NULL,
+  created_at TEXT NOT NULL,
+  expires_at TEXT,
+  PRIMARY KEY (user_key, kind)
+);
+
+CREATE TABLE IF NOT EXISTS moderation_log (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  created_at TEXT NOT NULL,
+  moderator TEXT NOT NULL,
+  action TEXT NOT NULL,
+  target TEXT NOT NULL,
+  reason TEXT,
+  expires_at TEXT
+);
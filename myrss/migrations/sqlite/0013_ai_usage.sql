@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS ai_usage (
+  day TEXT NOT NULL,
+  bot TEXT NOT NULL,
+  user_key TEXT NOT NULL,
+  prompt_tokens INTEGER NOT NULL,
+  completion_tokens INTEGER NOT NULL,
+  requests INTEGER NOT NULL,
+  PRIMARY KEY (day, bot, user_key)
+);
@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0;
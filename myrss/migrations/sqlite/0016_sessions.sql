@@ -0,0 +1,2 @@
+ALTER TABLE messages ADD COLUMN session TEXT;
+ALTER TABLE reviews ADD COLUMN session TEXT;
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS name_claims (
+  key TEXT PRIMARY KEY,
+  session TEXT NOT NULL
+);
@@ -0,0 +1,334 @@
+//! Exporting the messages of a room along with the bots to an archive, and
+//! importing archives into another instance.
+//!
+//! Archives are JSON Lines with one record per line: first every bot, then
+//! every message in the order it was sent. HTML archives show the messages
+//! for reading and embed the same records, so they can be imported too.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context};
+use askama::Template;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ai::Bot,
+    models::{Message, Reaction},
+    render,
+    store::ChatStore,
+    templates::ArchiveTemplate,
+};
+
+/// The element HTML archives keep their records in
+const DATA_START: &str = r#"<script type="application/x-ndjson" id="archive-data">"#;
+const DATA_END: &str = "</script>";
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Bot(Bot),
+    Message(ArchivedMessage),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedMessage {
+    /// The id of the message in the instance it was exported from, which
+    /// replies refer to
+    pub id: i32,
+    pub parent_id: Option<i32>,
+    pub sender: String,
+    pub sent_date: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Only the Markdown is archived. It is rendered again on import so that
+    /// archives can't bring in HTML that didn't go through the sanitizer.
+    pub source: String,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+}
+
+impl ArchivedMessage {
+    fn new(message: &Message) -> Self {
+        Self {
+            id: message.id.unwrap_or_default(),
+            parent_id: message.parent_id,
+            sender: message.sender.clone(),
+            sent_date: message.sent_date,
+            edited_at: message.edited_at,
+            source: message.source.clone(),
+            pinned: message.pinned,
+            reactions: message.reactions.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Jsonl,
+    Html,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "jsonl" => Ok(Self::Jsonl),
+            "html" => Ok(Self::Html),
+            _ => bail!("Unknown archive format `{format}`, expected `jsonl` or `html`"),
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jsonl => "application/x-ndjson",
+            Self::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jsonl => "jsonl",
+            Self::Html => "html",
+        }
+    }
+}
+
+/// Export the threads of a room, or of the main feed if `room` is `None`,
+/// along with every bot. Direct messages are never exported.
+pub async fn export(
+    store: &dyn ChatStore,
+    room: Option<i32>,
+    format: Format,
+) -> anyhow::Result<String> {
+    let bots = store.bots().await?;
+    let mut threads = vec![];
+    for mut root in store.get_threads(room).await? {
+        let id = root.id.unwrap_or_default();
+        root.reactions = store.get_reactions(id).await?;
+        let mut replies = store.get_replies(id).await?;
+        for reply in replies.iter_mut() {
+            reply.reactions = store.get_reactions(reply.id.unwrap_or_default()).await?;
+        }
+        threads.push((root, replies));
+    }
+
+    let records = bots
+        .iter()
+        .cloned()
+        .map(Record::Bot)
+        .chain(threads.iter().flat_map(|(root, replies)| {
+            std::iter::once(root)
+                .chain(replies)
+                .map(|message| Record::Message(ArchivedMessage::new(message)))
+        }))
+        .map(|record| serde_json::to_string(&record))
+        .collect::<Result<Vec<_>, _>>()?;
+    let jsonl = records.join("\n") + "\n";
+    match format {
+        Format::Jsonl => Ok(jsonl),
+        Format::Html => Ok(ArchiveTemplate {
+            room,
+            exported_at: Utc::now(),
+            threads,
+            bots,
+            // `<` can only be in strings, where escaping it keeps the records
+            // from closing the script element they are in
+            data: jsonl.replace('<', "\\u003c"),
+        }
+        .render()?),
+    }
+}
+
+pub struct ImportSummary {
+    pub messages: usize,
+    /// The bots that were imported. Bots with the same name as an existing
+    /// bot are skipped.
+    pub bots: Vec<Bot>,
+    pub skipped_bots: Vec<String>,
+}
+
+/// Import an archive in either format into a room, or into the main feed if
+/// `room` is `None`. Imported messages get new ids.
+pub async fn import(
+    store: &dyn ChatStore,
+    archive: &str,
+    room: Option<i32>,
+) -> anyhow::Result<ImportSummary> {
+    let records = parse(archive)?;
+    check_replies(&records)?;
+    let existing_bots = store.bots().await?;
+    let mut summary = ImportSummary {
+        messages: 0,
+        bots: vec![],
+        skipped_bots: vec![],
+    };
+    // Maps the ids in the archive to the ids of the imported messages
+    let mut ids = HashMap::new();
+    for record in records {
+        match record {
+            Record::Bot(bot) => {
+                let taken = existing_bots
+                    .iter()
+                    .chain(&summary.bots)
+                    .any(|existing| existing.name().eq_ignore_ascii_case(bot.name()));
+                if taken {
+                    summary.skipped_bots.push(bot.name().to_string());
+                } else {
+                    store.save_bot(&bot).await?;
+                    summary.bots.push(bot);
+                }
+            }
+            Record::Message(archived) => {
+                let parent_id = archived
+                    .parent_id
+                    .and_then(|parent_id| ids.get(&parent_id).copied());
+                let message = Message {
+                    id: None,
+                    parent_id,
+                    sender: archived.sender,
+                    recipient: None,
+                    sent_date: archived.sent_date,
+                    edited_at: archived.edited_at,
+                    contents: render::render_message(&archived.source),
+                    source: archived.source,
+                    pinned: archived.pinned,
+                    hidden: false,
+                    session: None,
+                    should_notify: false,
+                    reply_count: 0,
+                    reactions: vec![],
+                    attachments: vec![],
+                    previews: vec![],
+                };
+                let id = store.insert_message(&message).await?;
+                if message.edited_at.is_some() {
+                    store
+                        .update_message(&Message {
+                            id: Some(id),
+                            ..message.clone()
+                        })
+                        .await?;
+                }
+                if message.pinned {
+                    store.set_pinned(id, true).await?;
+                }
+                if let (Some(room), None) = (room, parent_id) {
+                    store.add_to_room(room, id).await?;
+                }
+                for reaction in archived.reactions {
+                    for user in reaction.users {
+                        store.toggle_reaction(id, &user, &reaction.emoji).await?;
+                    }
+                }
+                ids.insert(archived.id, id);
+                summary.messages += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Get the records of an archive, taking them out of the HTML if it is an
+/// HTML archive
+fn parse(archive: &str) -> anyhow::Result<Vec<Record>> {
+    let jsonl = match archive.split_once(DATA_START) {
+        Some((_, data)) => data
+            .split_once(DATA_END)
+            .map(|(data, _)| data)
+            .context("The HTML archive's data is not closed")?,
+        None => archive,
+    };
+    jsonl
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid record on line {}", number + 1))
+        })
+        .collect()
+}
+
+/// Check that every reply comes after the message it replies to, so that
+/// nothing is imported from an archive that can't be imported completely
+fn check_replies(records: &[Record]) -> anyhow::Result<()> {
+    let mut ids = HashSet::new();
+    for record in records {
+        let Record::Message(message) = record else {
+            continue;
+        };
+        if let Some(parent_id) = message.parent_id.filter(|id| !ids.contains(id)) {
+            bail!(
+                "Message {} replies to message {parent_id}, which comes after it or isn't in \
+                the archive",
+                message.id
+            );
+        }
+        ids.insert(message.id);
+    }
+    Ok(())
+}
+
+/// Run `export` or `import` from the command line:
+///
+/// - `export [--room <id>] [--format jsonl|html]` writes the archive to
+///   standard output
+/// - `import [--room <id>] <file>` imports an archive, from standard input if
+///   the file is `-`
+#[cfg(not(feature = "shuttle"))]
+pub async fn run_command(store: &dyn ChatStore, args: &[String]) -> anyhow::Result<()> {
+    let mut room = None;
+    let mut format = Format::default();
+    let mut file = None;
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--room" => {
+                let value = rest.next().context("--room needs a room id")?;
+                room = Some(value.parse().context("Invalid room id")?);
+            }
+            "--format" => {
+                format = rest.next().context("--format needs a format")?.parse()?;
+            }
+            _ if file.is_none() => file = Some(arg.as_str()),
+            _ => bail!("Unexpected argument `{arg}`"),
+        }
+    }
+
+    match args.first().map(String::as_str) {
+        Some("export") => {
+            if let Some(arg) = file {
+                bail!("Unexpected argument `{arg}`");
+            }
+            print!("{}", export(store, room, format).await?);
+        }
+        Some("import") => {
+            let archive = match file.context("No archive to import given")? {
+                "-" => std::io::read_to_string(std::io::stdin())?,
+                path => std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {path}"))?,
+            };
+            let summary = import(store, &archive, room).await?;
+            println!(
+                "Imported {} messages and {} bots.",
+                summary.messages,
+                summary.bots.len()
+            );
+            if !summary.skipped_bots.is_empty() {
+                println!(
+                    "Skipped bots that already exist: {}",
+                    summary.skipped_bots.join(", ")
+                );
+            }
+        }
+        _ => bail!("Unknown command, expected `export` or `import`"),
+    }
+    Ok(())
+}
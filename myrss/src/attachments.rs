@@ -0,0 +1,364 @@
+//! Files attached to messages. Uploads are checked against the limits set in
+//! the environment, images get a thumbnail, and the files are kept in a
+//! [`FileStorage`], which is a directory on disk for now but could as well be
+//! an object store.
+
+use std::{io::Cursor, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use image::{ImageFormat, ImageReader, Limits};
+use thiserror::Error;
+
+use crate::{models::Attachment, store::ChatStore};
+
+/// The most files that can be attached to one message
+pub const MAX_FILES: usize = 4;
+/// Room for the text fields of a message on top of its files
+const MAX_FORM_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_TYPES: &str =
+    "image/png,image/jpeg,image/gif,image/webp,application/pdf,text/plain,application/zip";
+const DEFAULT_DIR: &str = "data/attachments";
+/// Thumbnails fit in a square this many pixels wide
+const THUMBNAIL_SIZE: u32 = 320;
+/// Larger images aren't decoded, so that a small file can't take up
+/// gigabytes of memory once decoded
+const MAX_IMAGE_DIMENSION: u32 = 10_000;
+const MAX_FILENAME_CHARS: usize = 200;
+
+/// Where the files of attachments are kept
+#[async_trait]
+pub trait FileStorage: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()>;
+    /// Get a file, or `None` if there is no file with that key
+    async fn get(&self, key: &str) -> std::io::Result<Option<Vec<u8>>>;
+    /// Delete a file, doing nothing if there is no file with that key
+    async fn delete(&self, key: &str) -> std::io::Result<()>;
+}
+
+/// Keeps every file in one directory, named by its key
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    /// Use a directory, creating it if it doesn't exist
+    pub fn new(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &str) -> std::io::Result<PathBuf> {
+        // Keys are made by the server, but a key that could point outside of
+        // the directory should never be used as a path
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid file key `{key}`"),
+            ));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+#[async_trait]
+impl FileStorage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        tokio::fs::write(self.path(key)?, data).await
+    }
+
+    async fn get(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Open the file storage in `ATTACHMENTS_DIR`
+pub fn open_storage() -> anyhow::Result<Arc<dyn FileStorage>> {
+    let dir = std::env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| DEFAULT_DIR.to_string());
+    let storage = LocalStorage::new(&dir)
+        .with_context(|| format!("Failed to create the attachments directory {dir}"))?;
+    Ok(Arc::new(storage))
+}
+
+/// The key the thumbnail of an attachment is kept under
+pub fn thumbnail_key(key: &str) -> String {
+    format!("{key}-thumbnail")
+}
+
+/// What can be attached to messages
+pub struct AttachmentLimits {
+    /// The size of the largest file that can be attached
+    pub max_bytes: usize,
+    /// The content types of the files that can be attached. Types ending in
+    /// `/*` allow every subtype.
+    pub allowed_types: Vec<String>,
+}
+
+impl AttachmentLimits {
+    /// Read the limits from `ATTACHMENT_MAX_BYTES` and `ATTACHMENT_TYPES`
+    pub fn from_env() -> Self {
+        let max_bytes = std::env::var("ATTACHMENT_MAX_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES);
+        let allowed_types = std::env::var("ATTACHMENT_TYPES")
+            .unwrap_or_else(|_| DEFAULT_TYPES.to_string())
+            .split(',')
+            .map(|content_type| content_type.trim().to_lowercase())
+            .filter(|content_type| !content_type.is_empty())
+            .collect();
+        Self {
+            max_bytes,
+            allowed_types,
+        }
+    }
+
+    /// The size of the largest message that can be sent, with every file at
+    /// the maximum size
+    pub fn body_limit(&self) -> usize {
+        self.max_bytes
+            .saturating_mul(MAX_FILES)
+            .saturating_add(MAX_FORM_BYTES)
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        self.allowed_types.iter().any(|allowed| {
+            allowed == content_type
+                || allowed.strip_suffix("/*").is_some_and(|kind| {
+                    content_type
+                        .strip_prefix(kind)
+                        .is_some_and(|subtype| subtype.starts_with('/'))
+                })
+        })
+    }
+
+    /// Check that a file can be attached, returning the content type it is
+    /// served with
+    pub fn check(&self, upload: &Upload) -> Result<String, UploadError> {
+        if upload.data.len() > self.max_bytes {
+            return Err(UploadError::TooLarge {
+                filename: upload.filename.clone(),
+                limit: self.max_bytes,
+            });
+        }
+        let content_type = content_type(upload);
+        if !self.allows(&content_type) {
+            return Err(UploadError::NotAllowed {
+                filename: upload.filename.clone(),
+                content_type,
+            });
+        }
+        Ok(content_type)
+    }
+}
+
+/// A file as it was uploaded, before it is checked
+pub struct Upload {
+    pub filename: String,
+    /// The content type the browser sent
+    pub content_type: Option<String>,
+    pub data: Bytes,
+}
+
+impl Upload {
+    pub fn new(filename: Option<&str>, content_type: Option<&str>, data: Bytes) -> Self {
+        Self {
+            filename: clean_filename(filename.unwrap_or_default()),
+            content_type: content_type.map(str::to_string),
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum UploadError {
+    #[error("{filename} is larger than the limit of {limit} bytes")]
+    TooLarge { filename: String, limit: usize },
+    #[error("{filename} can't be attached, since files of type {content_type} aren't allowed")]
+    NotAllowed {
+        filename: String,
+        content_type: String,
+    },
+    #[error("At most {MAX_FILES} files can be attached to a message")]
+    TooMany,
+}
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::NotAllowed { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::TooMany => StatusCode::BAD_REQUEST,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// Get the content type of an upload. Images are recognized by their
+/// contents, since browsers only guess the type from the file extension.
+/// Anything claiming to be an image that isn't one is treated as an unknown
+/// binary file, so that it can't be shown as one.
+fn content_type(upload: &Upload) -> String {
+    let declared = upload
+        .content_type
+        .as_deref()
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|content_type| content_type.trim().to_lowercase())
+        .filter(|content_type| !content_type.is_empty());
+    match (image::guess_format(&upload.data), declared) {
+        (Ok(format), _) => format.to_mime_type().to_string(),
+        (Err(_), Some(declared)) if !declared.starts_with("image/") => declared,
+        _ => "application/octet-stream".to_string(),
+    }
+}
+
+/// Keep only the name of an uploaded file, without any path or control
+/// characters
+fn clean_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILENAME_CHARS)
+        .collect::<String>();
+    match name.trim() {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Save the checked uploads of a message, along with thumbnails for the
+/// images, and return the attachments. Uploads that fail to save are logged
+/// and left out, like messages that fail to save are still sent.
+pub async fn save(
+    files: &dyn FileStorage,
+    store: &dyn ChatStore,
+    message: i32,
+    uploads: Vec<(Upload, String)>,
+) -> Vec<Attachment> {
+    let mut attachments = vec![];
+    for (upload, content_type) in uploads {
+        match save_upload(files, store, message, upload, content_type).await {
+            Ok(attachment) => attachments.push(attachment),
+            Err(e) => log::error!("Failed to save attachment:\n{e:#}"),
+        }
+    }
+    attachments
+}
+
+async fn save_upload(
+    files: &dyn FileStorage,
+    store: &dyn ChatStore,
+    message: i32,
+    upload: Upload,
+    content_type: String,
+) -> anyhow::Result<Attachment> {
+    let key = uuid::Uuid::new_v4().simple().to_string();
+    files.put(&key, &upload.data).await?;
+    let thumbnail = if content_type.starts_with("image/") {
+        let data = upload.data.clone();
+        tokio::task::spawn_blocking(move || thumbnail(&data)).await?
+    } else {
+        None
+    };
+    if let Some(thumbnail) = &thumbnail {
+        files.put(&thumbnail_key(&key), thumbnail).await?;
+    }
+    let attachment = Attachment {
+        message,
+        key,
+        filename: upload.filename,
+        content_type,
+        size: upload.data.len() as i64,
+        has_thumbnail: thumbnail.is_some(),
+    };
+    store.add_attachment(&attachment).await?;
+    Ok(attachment)
+}
+
+/// Make a PNG thumbnail of an image, or `None` if it can't be decoded
+fn thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    reader.limits(limits);
+    let image = match reader.decode() {
+        Ok(image) => image,
+        Err(e) => {
+            log::warn!("Failed to make a thumbnail:\n{e}");
+            return None;
+        }
+    };
+    let mut thumbnail = Cursor::new(vec![]);
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut thumbnail, ImageFormat::Png)
+        .ok()?;
+    Some(thumbnail.into_inner())
+}
+
+/// Delete the files of attachments, along with their thumbnails
+pub async fn delete_files(files: &dyn FileStorage, keys: &[String]) {
+    for key in keys {
+        for key in [key.clone(), thumbnail_key(key)] {
+            if let Err(e) = files.delete(&key).await {
+                log::error!("Failed to delete attachment file {key}:\n{e}");
+            }
+        }
+    }
+}
+
+/// Get the `Content-Disposition` of an attachment. Only images are shown in
+/// the browser, everything else is downloaded.
+pub fn content_disposition(attachment: &Attachment) -> String {
+    let disposition = if attachment.content_type.starts_with("image/") {
+        "inline"
+    } else {
+        "attachment"
+    };
+    // Older browsers only understand the ASCII `filename`
+    let ascii = attachment
+        .filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let encoded = attachment
+        .filename
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{byte:02X}")
+            }
+        })
+        .collect::<String>();
+    format!("{disposition}; filename=\"{ascii}\"; filename*=UTF-8''{encoded}")
+}
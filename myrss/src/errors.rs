@@ -3,8 +3,12 @@ use axum::response::{IntoResponse, Response};
 
 pub enum ApiError {
     HTTPError(axum::http::Error),
-    #[allow(dead_code)]
+    DatabaseError(sqlx::Error),
+    /// Any other error that isn't the user's fault
+    Internal(anyhow::Error),
     DoesNotExist,
+    Forbidden,
+    BadRequest,
 }
 
 impl IntoResponse for ApiError {
@@ -15,7 +19,17 @@ impl IntoResponse for ApiError {
                 format!("HTTP error: {e}"),
             )
                 .into_response(),
+            Self::DatabaseError(e) => {
+                log::error!("Database error:\n{e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Self::Internal(e) => {
+                log::error!("Internal error:\n{e:#}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
             Self::DoesNotExist => StatusCode::NOT_FOUND.into_response(),
+            Self::Forbidden => StatusCode::FORBIDDEN.into_response(),
+            Self::BadRequest => StatusCode::BAD_REQUEST.into_response(),
         }
     }
 }
@@ -25,3 +39,15 @@ impl From<axum::http::Error> for ApiError {
         Self::HTTPError(e)
     }
 }
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Internal(e)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::DatabaseError(e)
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+use syntect::{
+    highlighting::ThemeSet,
+    html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+/// Every class emitted by the highlighter starts with this prefix, which is
+/// what the sanitizer uses to tell highlighter classes apart from anything
+/// else.
+pub const CLASS_PREFIX: &str = "hl-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed {
+    prefix: CLASS_PREFIX,
+};
+const THEME: &str = "InspiredGitHub";
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+/// Matches the code blocks `markdown` generates for fences with a language
+static CODE_BLOCK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?s)<pre><code class="language-([^"]+)">(.*?)</code></pre>"#).unwrap()
+});
+
+static STYLESHEET: LazyLock<String> = LazyLock::new(|| {
+    let themes = ThemeSet::load_defaults();
+    css_for_theme_with_class_style(&themes.themes[THEME], CLASS_STYLE).unwrap_or_else(|e| {
+        log::error!("Failed to generate highlighting stylesheet:\n{e}");
+        String::new()
+    })
+});
+
+/// Highlight the contents of every fenced code block in rendered message HTML
+/// whose language the highlighter knows. Blocks in other languages are left as
+/// they are.
+pub fn highlight_code_blocks(html: &str) -> String {
+    CODE_BLOCK
+        .replace_all(html, |caps: &Captures| {
+            let lang = &caps[1];
+            let Some(highlighted) = highlight(lang, &unescape(&caps[2])) else {
+                return caps[0].to_string();
+            };
+            format!("<pre><code class=\"language-{lang}\">{highlighted}</code></pre>")
+        })
+        .into_owned()
+}
+
+fn highlight(lang: &str, code: &str) -> Option<String> {
+    let syntax = SYNTAXES.find_syntax_by_token(lang)?;
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        if let Err(e) = generator.parse_html_for_line_which_includes_newline(line) {
+            log::warn!("Failed to highlight {lang} code block:\n{e}");
+            return None;
+        }
+    }
+    Some(generator.finalize())
+}
+
+/// Undo the escaping `markdown` applies to code block contents
+fn unescape(html: &str) -> String {
+    html.replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// CSS for the classes emitted by the highlighter
+pub fn stylesheet() -> &'static str {
+    &STYLESHEET
+}
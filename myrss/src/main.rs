@@ -1,22 +1,56 @@
 mod ai;
+mod archive;
+mod attachments;
 mod errors;
+mod highlight;
 mod models;
+mod moderation;
+mod names;
+mod presence;
+mod prompt;
+mod ratelimit;
+mod render;
+mod retention;
+mod retrieval;
 mod router;
 mod routes;
+mod screening;
+mod store;
 mod templates;
+mod tools;
+mod triggers;
+mod unfurl;
+mod usage;
 
 #[cfg(feature = "shuttle")]
 #[shuttle_runtime::main]
 async fn main(
     #[shuttle_runtime::Secrets] secrets: shuttle_runtime::SecretStore,
+    #[shuttle_shared_db::Postgres] db: sqlx::PgPool,
 ) -> shuttle_axum::ShuttleAxum {
     let groq_api_key = secrets.get("GROQ_API_KEY").unwrap();
-    let router = router::init_router(groq_api_key).await;
+    let admin_token = secrets.get("ADMIN_TOKEN");
+    let moderator_token = secrets.get("MODERATOR_TOKEN");
+    let store = store::PostgresStore::new(db)
+        .await
+        .expect("Failed to run database migrations");
+    let files = attachments::open_storage().expect("Failed to open the attachment storage");
+    let router = router::init_router(
+        groq_api_key,
+        admin_token,
+        moderator_token,
+        std::sync::Arc::new(store),
+        files,
+    )
+    .await;
 
     Ok(router.into())
 }
 
 const DEFAULT_PORT: u16 = 3000;
+/// A local SQLite database next to the saved bots
+#[cfg(not(feature = "shuttle"))]
+const DEFAULT_DATABASE_URL: &str = "sqlite://data/myrss.db";
 
 #[cfg(not(feature = "shuttle"))]
 #[tokio::main]
@@ -25,10 +59,16 @@ async fn main() {
 
     env_logger::init();
 
-    let groq_api_key = option_env!("GROQ_API_KEY")
+    let database_url = option_env!("DATABASE_URL")
         .map(|v| v.to_string())
-        .or_else(|| std::env::var("GROQ_API_KEY").ok())
-        .expect("No Groq API key available");
+        .or_else(|| std::env::var("DATABASE_URL").ok())
+        .unwrap_or_else(|| DEFAULT_DATABASE_URL.to_string());
+    let admin_token = option_env!("ADMIN_TOKEN")
+        .map(|v| v.to_string())
+        .or_else(|| std::env::var("ADMIN_TOKEN").ok());
+    let moderator_token = option_env!("MODERATOR_TOKEN")
+        .map(|v| v.to_string())
+        .or_else(|| std::env::var("MODERATOR_TOKEN").ok());
 
     let addr = match std::env::var("RSS_DO_NOT_PUBLISH") {
         Ok(s) if s == "1" => Ipv4Addr::new(127, 0, 0, 1),
@@ -38,8 +78,37 @@ async fn main() {
         Ok(Ok(port)) => port,
         _ => DEFAULT_PORT,
     };
-    let router = router::init_router(groq_api_key).await;
+    if database_url == DEFAULT_DATABASE_URL {
+        std::fs::create_dir_all("data").expect("Failed to create the data directory");
+    }
+    let store = store::connect(&database_url)
+        .await
+        .expect("Failed to open the database");
+
+    // `export` and `import` work on the database without starting the server
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        if let Err(e) = archive::run_command(store.as_ref(), &args).await {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let groq_api_key = option_env!("GROQ_API_KEY")
+        .map(|v| v.to_string())
+        .or_else(|| std::env::var("GROQ_API_KEY").ok())
+        .expect("No Groq API key available");
+    let files = attachments::open_storage().expect("Failed to open the attachment storage");
+    let router =
+        router::init_router(groq_api_key, admin_token, moderator_token, store, files).await;
     let listener = tokio::net::TcpListener::bind((addr, port)).await.unwrap();
 
-    axum::serve(listener, router).await.unwrap();
+    // The address of every connection is kept for the rate limits
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -1,15 +1,472 @@
-use chrono::Utc;
+use chrono::{NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::types::chrono::DateTime;
 
 #[derive(sqlx::FromRow, Serialize, Deserialize, Clone)]
 pub struct Message {
+    /// The id of the message in the database. Messages that are only
+    /// broadcast, like join and leave notices, don't have one.
+    pub id: Option<i32>,
+    /// The message this is a reply to. Replies are always to the first
+    /// message of a thread, never to other replies.
+    pub parent_id: Option<i32>,
     pub sender: String,
+    /// The key (see [`user_key`]) of the user a direct message is sent to.
+    /// Only the sender and the recipient can see direct messages.
+    pub recipient: Option<String>,
     pub sent_date: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    /// The rendered HTML of the message
     pub contents: String,
+    /// The Markdown the message was rendered from
+    pub source: String,
+    /// Pinned messages are never deleted by retention policies
+    #[sqlx(default)]
+    pub pinned: bool,
+    /// Hidden messages show a placeholder until an admin reviews them, see
+    /// [`crate::screening`]
+    #[sqlx(default)]
+    pub hidden: bool,
+    /// The session the message was sent from, which lets its sender edit and
+    /// delete it. It is never sent to clients or exported.
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub session: Option<String>,
+    #[sqlx(default)]
     pub should_notify: bool,
+    #[sqlx(default)]
+    pub reply_count: i64,
+    #[sqlx(skip)]
+    pub reactions: Vec<Reaction>,
+    #[sqlx(skip)]
+    pub attachments: Vec<Attachment>,
+    /// Previews of the links in the message
+    #[sqlx(skip)]
+    pub previews: Vec<LinkPreview>,
 }
 #[derive(sqlx::FromRow, Serialize, Deserialize)]
 pub struct MessageNew {
     pub contents: String,
+    /// The thread the message is sent in, if any
+    pub parent_id: Option<i32>,
+    /// The user the message is sent to directly, if any
+    pub recipient: Option<String>,
+}
+
+impl Message {
+    pub fn is_visible_to(&self, user: &str) -> bool {
+        match &self.recipient {
+            Some(recipient) => {
+                let user = user_key(user);
+                *recipient == user || user_key(&self.sender) == user
+            }
+            None => true,
+        }
+    }
+
+    /// Whether the message was sent from a session
+    pub fn is_sent_from(&self, session: Option<&str>) -> bool {
+        session.is_some() && self.session.as_deref() == session
+    }
+}
+
+/// Names are free text, so users are identified by their name in lowercase
+/// and without spaces wherever they are referred to by other users
+pub fn user_key(name: &str) -> String {
+    name.replace(' ', "").to_lowercase()
+}
+
+/// All reactions to a message with one emoji
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone)]
+pub struct Reaction {
+    pub emoji: String,
+    /// The users who reacted, in the order they reacted
+    pub users: Vec<String>,
+}
+
+impl Reaction {
+    pub fn reacted_by(&self, user: &str) -> bool {
+        self.users.iter().any(|u| u == user)
+    }
+}
+
+/// A file uploaded along with a message
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone)]
+pub struct Attachment {
+    pub message: i32,
+    /// The key the file is kept under in the file storage, which is also the
+    /// id it is downloaded by
+    pub key: String,
+    /// The name of the file as it was uploaded
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    /// Whether a thumbnail was made, which is only done for images
+    pub has_thumbnail: bool,
+}
+
+impl Attachment {
+    /// Get the size of the file for showing to users
+    pub fn size_label(&self) -> String {
+        const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+        if self.size < 1024 {
+            return format!("{} B", self.size);
+        }
+        let mut size = self.size as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// What was found on the page at a link in a message, to show under the
+/// message
+#[derive(sqlx::FromRow, Serialize, Deserialize, Clone)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    /// When the page was fetched. Pages that couldn't be fetched are kept
+    /// without a title or description, so that they aren't fetched again for
+    /// every message linking to them.
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl LinkPreview {
+    /// Whether there is anything to show
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.description.is_none()
+    }
+}
+
+#[derive(sqlx::FromRow, Clone)]
+pub struct Room {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+}
+
+/// Limits on how long messages are kept in a room. Limits apply to threads
+/// as a whole: replies are only deleted along with the message they reply
+/// to, and pinned messages are always kept and don't count towards the
+/// maximum count.
+#[derive(sqlx::FromRow, Clone, Default)]
+pub struct RetentionPolicy {
+    /// The room the policy is for, or `None` for messages that aren't in a
+    /// room, which includes the main feed and direct messages
+    pub room: Option<i32>,
+    pub max_age_days: Option<i32>,
+    /// The most threads to keep, newest first
+    pub max_count: Option<i32>,
+}
+
+impl RetentionPolicy {
+    /// Messages sent before this are too old to keep
+    pub fn cutoff(&self) -> Option<DateTime<Utc>> {
+        self.max_age_days
+            .map(|days| Utc::now() - chrono::Duration::days(days.into()))
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_days.is_none() && self.max_count.is_none()
+    }
+}
+
+/// A user who has connected to the chat
+#[derive(sqlx::FromRow, Clone)]
+pub struct User {
+    pub key: String,
+    /// The name the user last connected with
+    pub name: String,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// What a sanction keeps a user from doing
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SanctionKind {
+    /// Muted users can't send messages or react to them
+    Mute,
+    /// Banned users can't use the chat at all
+    Ban,
+}
+
+impl SanctionKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Mute => "mute",
+            Self::Ban => "ban",
+        }
+    }
+
+    pub fn past_tense(self) -> &'static str {
+        match self {
+            Self::Mute => "muted",
+            Self::Ban => "banned",
+        }
+    }
+}
+
+impl TryFrom<String> for SanctionKind {
+    type Error = String;
+
+    fn try_from(kind: String) -> Result<Self, Self::Error> {
+        match kind.as_str() {
+            "mute" => Ok(Self::Mute),
+            "ban" => Ok(Self::Ban),
+            _ => Err(format!("Unknown sanction `{kind}`")),
+        }
+    }
+}
+
+/// A mute or ban of a user
+#[derive(sqlx::FromRow, Clone)]
+pub struct Sanction {
+    /// The key of the user
+    pub user_key: String,
+    #[sqlx(try_from = "String")]
+    pub kind: SanctionKind,
+    pub reason: Option<String>,
+    /// The name of the moderator who imposed the sanction
+    pub moderator: String,
+    pub created_at: DateTime<Utc>,
+    /// When the sanction ends, or `None` if it is permanent
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModerationAction {
+    Mute,
+    Unmute,
+    Kick,
+    Ban,
+    Unban,
+}
+
+impl ModerationAction {
+    /// Get the action a command like `!mute` is for
+    pub fn from_command(command: &str) -> Option<Self> {
+        match command {
+            "mute" => Some(Self::Mute),
+            "unmute" => Some(Self::Unmute),
+            "kick" => Some(Self::Kick),
+            "ban" => Some(Self::Ban),
+            "unban" => Some(Self::Unban),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Mute => "mute",
+            Self::Unmute => "unmute",
+            Self::Kick => "kick",
+            Self::Ban => "ban",
+            Self::Unban => "unban",
+        }
+    }
+
+    /// The sanction the action imposes or lifts, if any
+    pub fn sanction(self) -> Option<SanctionKind> {
+        match self {
+            Self::Mute | Self::Unmute => Some(SanctionKind::Mute),
+            Self::Ban | Self::Unban => Some(SanctionKind::Ban),
+            Self::Kick => None,
+        }
+    }
+
+    /// Whether the action can be given a duration
+    pub fn has_duration(self) -> bool {
+        matches!(self, Self::Mute | Self::Ban)
+    }
+}
+
+impl TryFrom<String> for ModerationAction {
+    type Error = String;
+
+    fn try_from(action: String) -> Result<Self, Self::Error> {
+        Self::from_command(&action).ok_or_else(|| format!("Unknown moderation action `{action}`"))
+    }
+}
+
+/// An entry of the audit log, which records every moderation action
+#[derive(sqlx::FromRow, Clone)]
+pub struct AuditEntry {
+    pub created_at: DateTime<Utc>,
+    /// The name of the moderator who took the action
+    pub moderator: String,
+    #[sqlx(try_from = "String")]
+    pub action: ModerationAction,
+    /// The user the action was taken against, as the moderator typed them
+    pub target: String,
+    pub reason: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// What happens to the messages the screening classifier objects to in a
+/// room
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreenAction {
+    /// Messages aren't screened
+    Off,
+    /// Messages are posted, and admins are asked to review them
+    #[default]
+    Flag,
+    /// Messages are posted with their text hidden until an admin releases
+    /// them
+    Hide,
+    /// Messages are only posted once an admin releases them
+    Hold,
+}
+
+impl ScreenAction {
+    pub const ALL: [Self; 4] = [Self::Off, Self::Flag, Self::Hide, Self::Hold];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Flag => "flag",
+            Self::Hide => "hide",
+            Self::Hold => "hold",
+        }
+    }
+
+    pub fn past_tense(self) -> &'static str {
+        match self {
+            Self::Off => "allowed",
+            Self::Flag => "flagged",
+            Self::Hide => "hidden",
+            Self::Hold => "held",
+        }
+    }
+}
+
+impl TryFrom<String> for ScreenAction {
+    type Error = String;
+
+    fn try_from(action: String) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|a| a.as_str() == action)
+            .ok_or_else(|| format!("Unknown screening action `{action}`"))
+    }
+}
+
+/// How the messages in a room are screened. Rooms without a policy flag
+/// messages.
+#[derive(sqlx::FromRow, Clone)]
+pub struct ScreenPolicy {
+    /// The room the policy is for, or `None` for messages that aren't in a
+    /// room
+    pub room: Option<i32>,
+    #[sqlx(try_from = "String")]
+    pub action: ScreenAction,
+}
+
+/// A message the screening classifier objected to, waiting for an admin to
+/// review it
+#[derive(sqlx::FromRow, Clone)]
+pub struct Review {
+    pub id: i32,
+    /// The posted message, which flagged and hidden messages have
+    pub message: Option<i32>,
+    #[sqlx(try_from = "String")]
+    pub action: ScreenAction,
+    /// Why the classifier objected to the message
+    pub reason: String,
+    pub sender: String,
+    /// The message the message replies to, which held messages are posted
+    /// under when they are released
+    pub parent_id: Option<i32>,
+    /// The text of the message as it was sent
+    pub source: String,
+    pub created_at: DateTime<Utc>,
+    /// The session the message was sent from, which held messages are posted
+    /// with
+    #[sqlx(default)]
+    pub session: Option<String>,
+}
+
+/// The tokens a bot used answering one user on one day
+#[derive(sqlx::FromRow, Clone)]
+pub struct AiUsage {
+    /// The day in UTC
+    pub day: NaiveDate,
+    pub bot: String,
+    /// The key of the user who asked
+    pub user_key: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    /// How many responses the tokens were used for
+    pub requests: i64,
+}
+
+impl AiUsage {
+    pub fn total_tokens(&self) -> i64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Everything that is broadcast to the connected clients
+#[derive(Clone)]
+pub enum ChatEvent {
+    Message(Message),
+    Edit(Message),
+    Delete(i32),
+    Reactions {
+        message: i32,
+        reactions: Vec<Reaction>,
+        /// The keys of the users who can see the message if it is a direct
+        /// message
+        participants: Option<[String; 2]>,
+    },
+    Replies {
+        message: i32,
+        count: i64,
+    },
+    /// The previews of the links in a message, which are fetched after it is
+    /// sent
+    Previews {
+        message: i32,
+        previews: Vec<LinkPreview>,
+        /// The keys of the users who can see the message if it is a direct
+        /// message
+        participants: Option<[String; 2]>,
+    },
+    /// A message only one user sees, wherever they are in the chat
+    Notice {
+        /// The key of the user
+        user: String,
+        message: Message,
+    },
+    /// Close the streams of a user
+    Kick {
+        /// The key of the user
+        user: String,
+        reason: Option<String>,
+    },
+}
+
+impl ChatEvent {
+    /// Whether an event can be sent to a user
+    pub fn is_visible_to(&self, user: &str) -> bool {
+        match self {
+            Self::Message(message) | Self::Edit(message) => message.is_visible_to(user),
+            Self::Reactions {
+                participants: Some(participants),
+                ..
+            }
+            | Self::Previews {
+                participants: Some(participants),
+                ..
+            } => participants.contains(&user_key(user)),
+            Self::Notice { user: key, .. } | Self::Kick { user: key, .. } => *key == user_key(user),
+            _ => true,
+        }
+    }
 }
@@ -0,0 +1,173 @@
+//! Muting, kicking and banning users. Moderators are whoever signed in at
+//! `/admin` with the moderator or the admin token, and everything they do is
+//! recorded in an audit log.
+
+use chrono::{DateTime, Duration, Utc};
+use tokio::sync::broadcast::Sender;
+
+use crate::{
+    models::{user_key, AuditEntry, ChatEvent, ModerationAction, Sanction, SanctionKind},
+    store::ChatStore,
+};
+
+/// What a user who signed in at `/admin` can do
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Role {
+    /// Can mute, kick and ban users
+    Moderator,
+    /// Can also edit, delete and pin any message, and manage retention and
+    /// archives
+    Admin,
+}
+
+/// A moderation command, like `!mute <user> [duration] [reason]`
+pub struct Moderation {
+    pub action: ModerationAction,
+    /// The user the action is taken against, as the moderator typed them
+    pub user: String,
+    pub duration: Option<Duration>,
+    pub reason: Option<String>,
+}
+
+impl Moderation {
+    /// Parse the arguments of a moderation command. The duration is only
+    /// recognized right after the user, for the actions that can have one.
+    pub fn parse(action: ModerationAction, args: &str) -> Option<Self> {
+        let mut words = args.split_whitespace();
+        let user = words.next()?.to_string();
+        let mut rest = words.peekable();
+        let duration = match rest.peek().and_then(|word| parse_duration(word)) {
+            Some(duration) if action.has_duration() => {
+                rest.next();
+                Some(duration)
+            }
+            _ => None,
+        };
+        let reason = rest.collect::<Vec<_>>().join(" ");
+        Some(Self {
+            action,
+            user,
+            duration,
+            reason: (!reason.is_empty()).then_some(reason),
+        })
+    }
+}
+
+/// Parse a duration like `30s`, `10m`, `2h`, `7d` or `4w`
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = text.chars().last()?;
+    let amount = text[..text.len() - unit.len_utf8()].parse::<i64>().ok()?;
+    if amount <= 0 {
+        return None;
+    }
+    match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+/// Format when a sanction ends for showing to users
+pub fn format_expiry(expires_at: Option<DateTime<Utc>>) -> String {
+    match expires_at {
+        Some(expires_at) => format!("until {}", expires_at.format("%d %b, %Y - %H:%M UTC")),
+        None => "permanently".to_string(),
+    }
+}
+
+/// Get the sanction that keeps a user from sending messages, if any. Bans
+/// come before mutes.
+pub async fn blocking_sanction(
+    store: &dyn ChatStore,
+    name: &str,
+) -> sqlx::Result<Option<Sanction>> {
+    let mut sanctions = store.active_sanctions(Some(&user_key(name))).await?;
+    sanctions.sort_by_key(|sanction| sanction.kind != SanctionKind::Ban);
+    Ok(sanctions.into_iter().next())
+}
+
+/// Tell a user why they can't do something
+pub fn describe_sanction(sanction: &Sanction) -> String {
+    let mut text = format!(
+        "You are {} {}.",
+        sanction.kind.past_tense(),
+        format_expiry(sanction.expires_at)
+    );
+    if let Some(reason) = &sanction.reason {
+        text.push_str(&format!(" Reason: {reason}"));
+    }
+    text
+}
+
+/// Carry out a moderation command and record it in the audit log, returning
+/// the message announcing it
+pub async fn apply(
+    store: &dyn ChatStore,
+    tx: &Sender<ChatEvent>,
+    moderator: &str,
+    command: Moderation,
+) -> sqlx::Result<String> {
+    let key = user_key(&command.user);
+    let now = Utc::now();
+    let expires_at = command.duration.map(|duration| now + duration);
+    match (command.action, command.action.sanction()) {
+        (ModerationAction::Mute | ModerationAction::Ban, Some(kind)) => {
+            store
+                .add_sanction(&Sanction {
+                    user_key: key.clone(),
+                    kind,
+                    reason: command.reason.clone(),
+                    moderator: moderator.to_string(),
+                    created_at: now,
+                    expires_at,
+                })
+                .await?;
+        }
+        (ModerationAction::Unmute | ModerationAction::Unban, Some(kind))
+            if !store.remove_sanction(&key, kind).await? =>
+        {
+            return Ok(format!("{} isn't {}.", command.user, kind.past_tense()));
+        }
+        _ => {}
+    }
+    if matches!(
+        command.action,
+        ModerationAction::Kick | ModerationAction::Ban
+    ) {
+        // Nobody listening just means the user isn't online
+        let _ = tx.send(ChatEvent::Kick {
+            user: key,
+            reason: command.reason.clone(),
+        });
+    }
+    store
+        .log_moderation(&AuditEntry {
+            created_at: now,
+            moderator: moderator.to_string(),
+            action: command.action,
+            target: command.user.clone(),
+            reason: command.reason.clone(),
+            expires_at,
+        })
+        .await?;
+
+    let past = match command.action {
+        ModerationAction::Mute => "muted",
+        ModerationAction::Unmute => "unmuted",
+        ModerationAction::Kick => "kicked",
+        ModerationAction::Ban => "banned",
+        ModerationAction::Unban => "unbanned",
+    };
+    let mut announcement = format!("{} was {past} by {moderator}", command.user);
+    if command.action.has_duration() {
+        announcement.push_str(&format!(" {}", format_expiry(expires_at)));
+    }
+    announcement.push('.');
+    if let Some(reason) = command.reason {
+        announcement.push_str(&format!(" Reason: {reason}"));
+    }
+    Ok(announcement)
+}
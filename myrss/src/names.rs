@@ -0,0 +1,147 @@
+//! The rules for the names users go by. Names are only kept in a cookie, so
+//! they are checked everywhere a request is made under one, not just when
+//! they are chosen.
+
+use std::collections::HashSet;
+
+use thiserror::Error;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+const DEFAULT_MAX_CHARS: usize = 32;
+/// The names the server posts as, along with names users would expect to
+/// belong to the people running it
+const RESERVED: &[&str] = &["System", "Server", "Admin", "Moderator"];
+/// The characters names can have besides letters and numbers
+const PUNCTUATION: &[char] = &[' ', '-', '_', '.', '\''];
+
+#[derive(Error, Debug)]
+pub enum NameError {
+    #[error("Names can't be empty.")]
+    Empty,
+    #[error("Names can be at most {0} characters long.")]
+    TooLong(usize),
+    #[error("Names can only have letters, numbers, spaces and the characters - _ . '")]
+    InvalidCharacters,
+    #[error("It is reserved or looks too much like \"{0}\".")]
+    Reserved(String),
+    #[error("It has a word that isn't allowed in it.")]
+    Denied,
+    #[error("Someone else is already using it.")]
+    Taken,
+}
+
+pub struct NamePolicy {
+    max_chars: usize,
+    /// The skeletons of the words names can't have in them
+    denied: HashSet<String>,
+}
+
+impl NamePolicy {
+    /// Read the policy from `NAME_MAX_CHARS`, `NAME_DENY_LIST` and
+    /// `NAME_DENY_LIST_FILE`. The deny list is comma separated and the file has
+    /// one word on each line, and the words from both are denied.
+    pub fn from_env() -> Self {
+        let max_chars = std::env::var("NAME_MAX_CHARS")
+            .ok()
+            .and_then(|chars| chars.parse().ok())
+            .unwrap_or(DEFAULT_MAX_CHARS);
+        let mut words = std::env::var("NAME_DENY_LIST")
+            .map(|list| list.split(',').map(str::to_string).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if let Ok(path) = std::env::var("NAME_DENY_LIST_FILE") {
+            match std::fs::read_to_string(&path) {
+                Ok(file) => words.extend(file.lines().map(str::to_string)),
+                Err(e) => log::error!("Failed to read the name deny list {path}:\n{e}"),
+            }
+        }
+        Self {
+            max_chars,
+            denied: words
+                .iter()
+                .map(|word| skeleton(word))
+                .filter(|word| !word.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Check a name a user wants to go by, which can't look like a reserved
+    /// name or any of `bots`. Names are expected to be trimmed.
+    pub fn check<'a>(
+        &self,
+        name: &str,
+        bots: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), NameError> {
+        if name.is_empty() {
+            return Err(NameError::Empty);
+        }
+        if name.chars().count() > self.max_chars {
+            return Err(NameError::TooLong(self.max_chars));
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || PUNCTUATION.contains(&c))
+        {
+            return Err(NameError::InvalidCharacters);
+        }
+        let name_skeleton = skeleton(name);
+        if name_skeleton.is_empty() {
+            return Err(NameError::InvalidCharacters);
+        }
+        if let Some(taken) = RESERVED
+            .iter()
+            .copied()
+            .chain(bots)
+            .find(|taken| skeleton(taken) == name_skeleton)
+        {
+            return Err(NameError::Reserved(taken.to_string()));
+        }
+        if self
+            .denied
+            .iter()
+            .any(|word| name_skeleton.contains(word.as_str()))
+        {
+            return Err(NameError::Denied);
+        }
+        Ok(())
+    }
+}
+
+/// Reduce a name to what it looks like, so that names that can be mistaken
+/// for each other get the same skeleton. Case, accents, punctuation and
+/// spacing are dropped, and letters from other scripts that look like Latin
+/// letters are replaced with them, along with digits that look like letters.
+pub fn skeleton(name: &str) -> String {
+    name.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .filter(|c| c.is_alphanumeric())
+        .map(|c| match c {
+            'а' | 'α' => 'a',
+            'в' | 'β' => 'b',
+            'с' | 'ϲ' => 'c',
+            'ԁ' => 'd',
+            'е' | 'ε' => 'e',
+            'ɡ' => 'g',
+            'һ' | 'н' => 'h',
+            // `i`, `l` and `1` are hard to tell apart in many fonts
+            'i' | 'ı' | 'і' | 'ι' | 'ӏ' | '1' => 'l',
+            'ј' => 'j',
+            'к' | 'κ' => 'k',
+            'м' => 'm',
+            'п' | 'η' => 'n',
+            'о' | 'ο' | 'σ' | 'օ' | '0' => 'o',
+            'р' | 'ρ' => 'p',
+            'ԛ' => 'q',
+            'ѕ' | '5' => 's',
+            'т' | 'τ' => 't',
+            'υ' => 'u',
+            'ν' => 'v',
+            'ԝ' | 'ω' => 'w',
+            'х' | 'χ' => 'x',
+            'у' | 'γ' => 'y',
+            _ => c,
+        })
+        .collect::<String>()
+        .replace("rn", "m")
+        .replace("vv", "w")
+}
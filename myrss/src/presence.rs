@@ -0,0 +1,48 @@
+//! Who is connected to the chat right now
+
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::models::user_key;
+
+/// The users with a stream open, by their key
+#[derive(Default)]
+pub struct Presence {
+    /// The name every user connected with last and how many streams they
+    /// have open
+    connections: Mutex<HashMap<String, (String, usize)>>,
+}
+
+impl Presence {
+    pub fn connect(&self, name: &str) {
+        let mut connections = self.connections.lock().unwrap();
+        let entry = connections
+            .entry(user_key(name))
+            .or_insert_with(|| (name.to_string(), 0));
+        entry.0 = name.to_string();
+        entry.1 += 1;
+    }
+
+    pub fn disconnect(&self, name: &str) {
+        let mut connections = self.connections.lock().unwrap();
+        let key = user_key(name);
+        if let Some(entry) = connections.get_mut(&key) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                connections.remove(&key);
+            }
+        }
+    }
+
+    /// Get the names of the users who are online, sorted
+    pub fn online(&self) -> Vec<String> {
+        let mut names = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        names.sort_by_key(|name| name.to_lowercase());
+        names
+    }
+}
@@ -0,0 +1,72 @@
+//! The system prompts of bots, which are rendered from a template with
+//! `{{ name }}` placeholders. The template in `aisysmsg.txt` is built in, but
+//! `AI_SYSTEM_PROMPT_FILE` can name a file to use instead, which is read
+//! again for every prompt so that it can be changed while the server runs.
+
+use chrono::NaiveDate;
+
+const DEFAULT_TEMPLATE: &str = include_str!("aisysmsg.txt");
+
+/// What the placeholders of a template are filled in with
+pub struct PromptVars<'a> {
+    pub bot_name: &'a str,
+    pub creator: &'a str,
+    pub language: &'a str,
+    pub custom_config: &'a str,
+    /// The room the bot was asked in, or `None` for the main feed
+    pub room: Option<&'a str>,
+    pub date: NaiveDate,
+}
+
+/// Render the system prompt of a bot
+pub fn render(vars: &PromptVars) -> String {
+    fill(template().trim_end(), vars)
+}
+
+/// Get the template from `AI_SYSTEM_PROMPT_FILE`, falling back to the built
+/// in one if it can't be read
+fn template() -> String {
+    let Ok(path) = std::env::var("AI_SYSTEM_PROMPT_FILE") else {
+        return DEFAULT_TEMPLATE.to_string();
+    };
+    std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        log::error!("Failed to read the system prompt template {path}, using the default:\n{e}");
+        DEFAULT_TEMPLATE.to_string()
+    })
+}
+
+/// Fill in the placeholders of a template in one pass, so that values with
+/// placeholders in them, like instructions, are left as they are. Unknown
+/// placeholders are left as they are too.
+fn fill(template: &str, vars: &PromptVars) -> String {
+    let mut prompt = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}").map(|end| end + 2) else {
+            break;
+        };
+        let placeholder = &rest[start..start + len];
+        // An unclosed placeholder is left as it is, without taking the next
+        if placeholder[2..].contains("{{") {
+            prompt.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        prompt.push_str(&rest[..start]);
+        match placeholder[2..placeholder.len() - 2].trim() {
+            "bot_name" => prompt.push_str(vars.bot_name),
+            "creator" => prompt.push_str(vars.creator),
+            "language" => prompt.push_str(vars.language),
+            "custom_config" => prompt.push_str(vars.custom_config),
+            "room" => match vars.room {
+                Some(room) => prompt.push_str(&format!("the room \"{room}\"")),
+                None => prompt.push_str("the main feed"),
+            },
+            "date" => prompt.push_str(&vars.date.format("%A, %-d %B %Y").to_string()),
+            _ => prompt.push_str(placeholder),
+        }
+        rest = &rest[start + len..];
+    }
+    prompt.push_str(rest);
+    prompt
+}
@@ -0,0 +1,202 @@
+//! Token bucket rate limits on sending messages and asking bots, which are
+//! kept per user and per IP address. Every bucket holds up to a burst of
+//! tokens and refills at a steady rate, and every message or query takes a
+//! token from both the bucket of the user and of their address.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+
+use crate::router::AppState;
+
+/// How many buckets are kept before the full ones are dropped. Full buckets
+/// are the same as no bucket, so dropping them only saves memory.
+const MAX_BUCKETS: usize = 10_000;
+
+/// The size and refill rate of a bucket
+#[derive(Clone, Copy)]
+pub struct Limit {
+    burst: f64,
+    per_minute: f64,
+}
+
+impl Limit {
+    /// Read a limit like `10/30`, meaning bursts of up to 10 that refill at 30
+    /// a minute, from an environment variable. `off` turns the limit off.
+    fn from_env(var: &str, default: Option<Limit>) -> Option<Limit> {
+        let Ok(value) = std::env::var(var) else {
+            return default;
+        };
+        if value == "off" {
+            return None;
+        }
+        let parsed = value.split_once('/').and_then(|(burst, per_minute)| {
+            Some(Limit {
+                burst: burst.trim().parse().ok()?,
+                per_minute: per_minute.trim().parse().ok()?,
+            })
+        });
+        match parsed {
+            Some(limit) if limit.burst >= 1.0 && limit.per_minute > 0.0 => Some(limit),
+            _ => {
+                log::error!("Invalid rate limit `{value}` in {var}, using the default");
+                default
+            }
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum BucketKey {
+    User(String),
+    Ip(IpAddr),
+}
+
+/// The buckets for one kind of request
+pub struct RateLimiter {
+    user: Option<Limit>,
+    ip: Option<Limit>,
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+}
+
+impl RateLimiter {
+    fn new(user: Option<Limit>, ip: Option<Limit>) -> Self {
+        Self {
+            user,
+            ip,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the buckets of a user and their address, or get how
+    /// long to wait until there are tokens in both. Nothing is taken unless
+    /// both have one.
+    pub fn take(&self, user: &str, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let checks = [
+            self.user
+                .map(|limit| (BucketKey::User(user.to_string()), limit)),
+            ip.zip(self.ip)
+                .map(|(ip, limit)| (BucketKey::Ip(ip), limit)),
+        ];
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|key, bucket| {
+                let limit = match key {
+                    BucketKey::User(_) => self.user,
+                    BucketKey::Ip(_) => self.ip,
+                };
+                limit.is_some_and(|limit| refill(bucket, limit, now) < limit.burst)
+            });
+        }
+        let mut wait = Duration::ZERO;
+        for (key, limit) in checks.iter().flatten() {
+            let bucket = buckets.get_mut(key);
+            let tokens = bucket.map_or(limit.burst, |bucket| refill(bucket, *limit, now));
+            if tokens < 1.0 {
+                let seconds = (1.0 - tokens) / limit.per_minute * 60.0;
+                wait = wait.max(Duration::from_secs_f64(seconds));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (key, limit) in checks.into_iter().flatten() {
+            let bucket = buckets.entry(key).or_insert(Bucket {
+                tokens: limit.burst,
+                updated: now,
+            });
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+/// Add the tokens a bucket gained since it was last updated, returning how
+/// many it has
+fn refill(bucket: &mut Bucket, limit: Limit, now: Instant) -> f64 {
+    let minutes = now.duration_since(bucket.updated).as_secs_f64() / 60.0;
+    bucket.tokens = (bucket.tokens + minutes * limit.per_minute).min(limit.burst);
+    bucket.updated = now;
+    bucket.tokens
+}
+
+pub struct RateLimits {
+    pub messages: RateLimiter,
+    /// Bot queries cost API quota, so they get stricter limits on top of the
+    /// limits on messages
+    pub bot_queries: RateLimiter,
+    /// Whether to take the address of users from the `X-Forwarded-For` header,
+    /// which is only right behind a proxy that sets it
+    trust_forwarded_for: bool,
+}
+
+impl RateLimits {
+    /// Read the limits from `RATE_LIMIT_MESSAGES`, `RATE_LIMIT_MESSAGES_IP`,
+    /// `RATE_LIMIT_BOTS` and `RATE_LIMIT_BOTS_IP`, and whether to trust the
+    /// forwarded address from `TRUST_FORWARDED_FOR`
+    pub fn from_env() -> Self {
+        let limit = |burst, per_minute| Some(Limit { burst, per_minute });
+        Self {
+            messages: RateLimiter::new(
+                Limit::from_env("RATE_LIMIT_MESSAGES", limit(10.0, 30.0)),
+                Limit::from_env("RATE_LIMIT_MESSAGES_IP", limit(30.0, 90.0)),
+            ),
+            bot_queries: RateLimiter::new(
+                Limit::from_env("RATE_LIMIT_BOTS", limit(3.0, 5.0)),
+                Limit::from_env("RATE_LIMIT_BOTS_IP", limit(6.0, 10.0)),
+            ),
+            trust_forwarded_for: std::env::var("TRUST_FORWARDED_FOR").is_ok_and(|v| v == "1"),
+        }
+    }
+}
+
+/// The address a request came from, if it is known
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let forwarded = state
+            .rate_limits
+            .trust_forwarded_for
+            .then(|| parts.headers.get("x-forwarded-for"))
+            .flatten()
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        let connected = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Ok(Self(forwarded.or(connected)))
+    }
+}
+
+/// Describe how long to wait before trying again
+pub fn format_wait(wait: Duration) -> String {
+    match wait.as_secs() + 1 {
+        1 => "a second".to_string(),
+        seconds if seconds < 120 => format!("{seconds} seconds"),
+        seconds => format!("{} minutes", seconds.div_ceil(60)),
+    }
+}
@@ -0,0 +1,314 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+use ammonia::Builder;
+use regex::{Captures, Regex};
+
+use crate::{highlight, models::user_key};
+
+/// Tags that can appear in a rendered message. This covers everything
+/// CommonMark produces except images, which are dropped so that messages
+/// can't make other clients load arbitrary remote content.
+const ALLOWED_TAGS: &[&str] = &[
+    "a",
+    "blockquote",
+    "br",
+    "code",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "span",
+    "strong",
+    "ul",
+];
+
+const ALLOWED_TAG_ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("a", &["class", "href", "title"]),
+    ("code", &["class"]),
+    ("ol", &["start"]),
+    ("span", &["class"]),
+];
+
+/// Prefixes of the classes allowed through the sanitizer: the code block
+/// language from `markdown`, the classes from the syntax highlighter and the
+/// class of mentions
+const ALLOWED_CLASS_PREFIXES: &[&str] = &["language-", highlight::CLASS_PREFIX, "mention"];
+
+/// Elements whose text is never searched for mentions
+const NO_MENTION_TAGS: &[&str] = &["a", "code", "pre"];
+
+/// Matches a tag, including any `>` in quoted attribute values
+static TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<(?:[^>"']|"[^"]*"|'[^']*')*>"#).unwrap());
+/// Matches `@name` at the start of a word. Names can contain dots and dashes,
+/// but not end with them.
+static MENTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(^|[^\w@])@(\w[\w.-]*\w|\w)").unwrap());
+static MENTION_LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<a class="mention"[^>]*>@([^<]+)</a>"#).unwrap());
+
+const ALLOWED_URL_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+static SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let tag_attributes = ALLOWED_TAG_ATTRIBUTES
+        .iter()
+        .map(|(tag, attrs)| (*tag, attrs.iter().copied().collect::<HashSet<_>>()))
+        .collect::<HashMap<_, _>>();
+    let mut builder = Builder::empty();
+    builder
+        .tags(ALLOWED_TAGS.iter().copied().collect())
+        .tag_attributes(tag_attributes)
+        .url_schemes(ALLOWED_URL_SCHEMES.iter().copied().collect())
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .attribute_filter(|_, attr, value| {
+            if attr != "class" {
+                return Some(value.into());
+            }
+            let classes = value
+                .split_whitespace()
+                .filter(|class| {
+                    ALLOWED_CLASS_PREFIXES
+                        .iter()
+                        .any(|prefix| class.starts_with(prefix))
+                })
+                .collect::<Vec<_>>();
+            if classes.is_empty() {
+                None
+            } else {
+                Some(Cow::Owned(classes.join(" ")))
+            }
+        });
+    builder
+});
+
+/// Render the Markdown source of a message to HTML. The sanitizer runs on the
+/// final HTML, so nothing the Markdown renderer or the syntax highlighter
+/// generates can get past it.
+pub fn render_message(source: &str) -> String {
+    let html = markdown::to_html(&hard_line_breaks(source));
+    let html = highlight::highlight_code_blocks(&html);
+    let html = link_mentions(&html);
+    sanitize_html(&html)
+}
+
+/// Turn every `@name` in the text of rendered HTML into a link to direct
+/// messages with that user, except in code and links
+fn link_mentions(html: &str) -> String {
+    replace_text(html, NO_MENTION_TAGS, mention_links)
+}
+
+fn mention_links(text: &str) -> Cow<'_, str> {
+    MENTION.replace_all(text, r#"$1<a class="mention" href="/dm/$2">@$2</a>"#)
+}
+
+/// Wrap every occurrence of the search terms in the text of rendered HTML in
+/// `<mark>`. Terms are matched case-insensitively and can only contain
+/// alphanumeric characters.
+pub fn highlight_terms(html: &str, terms: &[String]) -> String {
+    let terms = terms
+        .iter()
+        .filter(|term| !term.is_empty() && term.chars().all(char::is_alphanumeric))
+        .map(|term| regex::escape(term))
+        .collect::<Vec<_>>();
+    if terms.is_empty() {
+        return html.to_string();
+    }
+    // Character references are matched too so that terms inside them are
+    // left alone
+    let Ok(term) = Regex::new(&format!(r"(?i)&#?\w+;|({})", terms.join("|"))) else {
+        return html.to_string();
+    };
+    replace_text(html, &[], |text| {
+        term.replace_all(text, |caps: &Captures| match caps.get(1) {
+            Some(term) => format!("<mark>{}</mark>", term.as_str()),
+            None => caps[0].to_string(),
+        })
+    })
+}
+
+/// Replace the text of HTML outside of the given elements. Text is still
+/// escaped when it is passed to `replace`.
+fn replace_text(html: &str, skip_tags: &[&str], replace: impl Fn(&str) -> Cow<'_, str>) -> String {
+    let mut replaced = String::with_capacity(html.len());
+    let mut skip_depth = 0usize;
+    let mut text_start = 0;
+    for tag in TAG.find_iter(html) {
+        let text = &html[text_start..tag.start()];
+        if skip_depth == 0 {
+            replaced.push_str(&replace(text));
+        } else {
+            replaced.push_str(text);
+        }
+        let (closing, name) = tag_name(tag.as_str());
+        if skip_tags.contains(&name) {
+            skip_depth = if closing {
+                skip_depth.saturating_sub(1)
+            } else {
+                skip_depth + 1
+            };
+        }
+        replaced.push_str(tag.as_str());
+        text_start = tag.end();
+    }
+    replaced.push_str(&replace(&html[text_start..]));
+    replaced
+}
+
+/// Get whether a tag is a closing tag, and its name
+fn tag_name(tag: &str) -> (bool, &str) {
+    let tag = tag.trim_start_matches('<');
+    let (closing, tag) = match tag.strip_prefix('/') {
+        Some(tag) => (true, tag),
+        None => (false, tag),
+    };
+    let name = tag
+        .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .next()
+        .unwrap_or_default();
+    (closing, name)
+}
+
+/// Get the names mentioned in a rendered message
+pub fn mentions(html: &str) -> Vec<&str> {
+    MENTION_LINK
+        .captures_iter(html)
+        .filter_map(|caps| caps.get(1))
+        .map(|name| name.as_str())
+        .collect()
+}
+
+/// Whether a mention refers to a user
+pub fn mention_matches(mention: &str, name: &str) -> bool {
+    mention.to_lowercase() == user_key(name)
+}
+
+/// Turn every newline outside of fenced code blocks into a Markdown hard line
+/// break (two spaces + newline). Lines inside fenced code blocks are kept
+/// exactly as written.
+fn hard_line_breaks(source: &str) -> String {
+    let mut open_fence = None;
+    let mut lines = Vec::new();
+    for line in source.lines() {
+        let fence = fence_marker(line);
+        if open_fence.is_some() && fence != open_fence {
+            lines.push(format!("{line}\n"));
+            continue;
+        }
+        open_fence = if open_fence.is_some() { None } else { fence };
+        lines.push(format!("{}  \n", line.trim_end()));
+    }
+    lines.concat()
+}
+
+fn fence_marker(line: &str) -> Option<char> {
+    let line = line.trim_start();
+    ["```", "~~~"]
+        .into_iter()
+        .find(|fence| line.starts_with(fence))
+        .and_then(|fence| fence.chars().next())
+}
+
+/// Clean HTML against the message allowlist
+fn sanitize_html(html: &str) -> String {
+    SANITIZER.clean(html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether none of the tags in rendered HTML could run script. Text is
+    /// escaped, so only the tags need to be checked.
+    fn is_inert(html: &str) -> bool {
+        TAG.find_iter(html).all(|tag| {
+            let tag = tag.as_str().to_lowercase();
+            let (_, name) = tag_name(&tag);
+            ALLOWED_TAGS.contains(&name)
+                && !tag.contains("javascript:")
+                && !tag.contains("data:")
+                && !Regex::new(r"\son\w+\s*=").unwrap().is_match(&tag)
+        })
+    }
+
+    #[test]
+    fn drops_javascript_links() {
+        let html = render_message("[x](javascript:alert(1))");
+        assert!(is_inert(&html), "{html}");
+        assert!(html.contains(">x</a>"), "{html}");
+    }
+
+    #[test]
+    fn drops_data_links() {
+        let html =
+            render_message("[x](data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==)");
+        assert!(is_inert(&html), "{html}");
+    }
+
+    #[test]
+    fn escapes_raw_script() {
+        let html = render_message("<script>alert(1)</script>");
+        assert!(is_inert(&html), "{html}");
+        assert!(html.contains("&lt;script&gt;"), "{html}");
+    }
+
+    #[test]
+    fn escapes_image_handlers() {
+        let html = render_message("<img src=x onerror=alert(1)>");
+        assert!(is_inert(&html), "{html}");
+    }
+
+    #[test]
+    fn escapes_link_handlers() {
+        let html = render_message(r#"<a href="x" onclick="alert(1)">x</a>"#);
+        assert!(is_inert(&html), "{html}");
+    }
+
+    #[test]
+    fn sanitizer_drops_images_and_handlers() {
+        assert_eq!(sanitize_html("<img src=x onerror=alert(1)>"), "");
+        let html = sanitize_html(r#"<a href="https://example.com" onclick="alert(1)">x</a>"#);
+        assert!(is_inert(&html), "{html}");
+        assert!(html.contains(r#"href="https://example.com""#), "{html}");
+    }
+
+    #[test]
+    fn keeps_quotes_in_link_titles() {
+        let html = render_message(r#"[x](https://example.com "a\" onmouseover=\"alert(1)")"#);
+        assert!(!html.contains(r#"" onmouseover=""#), "{html}");
+        assert!(
+            html.contains("title=\"a&quot; onmouseover=&quot;alert(1)\""),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn only_keeps_allowed_classes() {
+        let html = sanitize_html(r#"<span class="language-x hl-y mention evil">x</span>"#);
+        assert_eq!(html, r#"<span class="language-x hl-y mention">x</span>"#);
+        assert_eq!(
+            sanitize_html(r#"<span class="evil">x</span>"#),
+            "<span>x</span>"
+        );
+        let html = render_message(r#"<span class="language-x hl-y mention evil">x</span>"#);
+        assert!(html.contains("&lt;span"), "{html}");
+        let html = render_message("```rust\nfn main() {}\n```");
+        assert!(html.contains(r#"class="language-rust""#), "{html}");
+        assert!(html.contains(r#"class="hl-"#), "{html}");
+    }
+
+    #[test]
+    fn links_mentions_outside_code() {
+        let html = render_message("@alice and `@bob`");
+        assert_eq!(mentions(&html), ["alice"]);
+    }
+}
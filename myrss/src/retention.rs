@@ -0,0 +1,108 @@
+//! Deleting old messages according to the retention policies of rooms
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::broadcast::Sender;
+
+use crate::{
+    attachments::{self, FileStorage},
+    models::{ChatEvent, Message, RetentionPolicy},
+    retrieval::ArchiveIndex,
+    store::ChatStore,
+};
+
+fn pruning_interval() -> Duration {
+    const DEFAULT_INTERVAL_MINUTES: u32 = 60;
+    // More minutes than fit in a u32 would be thousands of years, and these
+    // can't overflow when turned into seconds
+    let minutes: u32 = match std::env::var("RETENTION_INTERVAL_MINUTES") {
+        Ok(value) => match value.trim().parse() {
+            Ok(minutes) if minutes >= 1 => minutes,
+            _ => {
+                log::error!(
+                    "Invalid interval `{value}` in RETENTION_INTERVAL_MINUTES, using the default"
+                );
+                DEFAULT_INTERVAL_MINUTES
+            }
+        },
+        Err(_) => DEFAULT_INTERVAL_MINUTES,
+    };
+    Duration::from_secs(u64::from(minutes) * 60)
+}
+
+/// What a retention policy would delete if it was enforced now
+pub struct RetentionReport {
+    pub policy: RetentionPolicy,
+    /// The messages that would be deleted, oldest first. Their replies would
+    /// be deleted too.
+    pub messages: Vec<Message>,
+}
+
+impl RetentionReport {
+    /// The number of messages that would be deleted, including replies
+    pub fn total(&self) -> i64 {
+        self.messages
+            .iter()
+            .map(|message| 1 + message.reply_count)
+            .sum()
+    }
+}
+
+/// Get what every retention policy would delete, without deleting anything
+pub async fn report(store: &dyn ChatStore) -> sqlx::Result<Vec<RetentionReport>> {
+    let mut reports = vec![];
+    for policy in store.retention_policies().await? {
+        let messages = store.retention_candidates(&policy).await?;
+        reports.push(RetentionReport { policy, messages });
+    }
+    Ok(reports)
+}
+
+/// Delete everything the retention policies don't allow to be kept, and take
+/// it out of the archive index, returning the number of threads deleted
+pub async fn prune(
+    store: &dyn ChatStore,
+    files: &dyn FileStorage,
+    archive_index: &ArchiveIndex,
+    tx: &Sender<ChatEvent>,
+) -> sqlx::Result<usize> {
+    let mut deleted = 0;
+    for report in report(store).await? {
+        for message in report.messages {
+            let Some(id) = message.id else {
+                continue;
+            };
+            let replies = store.get_replies(id).await?;
+            let keys = store.delete_message(id).await?;
+            archive_index.remove(id);
+            for reply in replies.iter().filter_map(|reply| reply.id) {
+                archive_index.remove(reply);
+            }
+            attachments::delete_files(files, &keys).await;
+            // Nobody listening just means there are no clients to update
+            let _ = tx.send(ChatEvent::Delete(id));
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Prune messages in the background every `RETENTION_INTERVAL_MINUTES`
+pub fn spawn_pruning(
+    store: Arc<dyn ChatStore>,
+    files: Arc<dyn FileStorage>,
+    archive_index: Arc<ArchiveIndex>,
+    tx: Sender<ChatEvent>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(pruning_interval());
+        loop {
+            interval.tick().await;
+            match prune(store.as_ref(), files.as_ref(), &archive_index, &tx).await {
+                Ok(0) => {}
+                Ok(deleted) => log::info!("Retention policies deleted {deleted} threads"),
+                Err(e) => log::error!("Failed to enforce retention policies:\n{e}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,376 @@
+//! Finding the archived messages that are relevant to a question, so that
+//! bots can answer questions about earlier conversations. Public messages are
+//! kept in an in-memory BM25 index, which is built when the server starts and
+//! updated as messages are sent, edited and deleted, including by retention
+//! policies. Messages that are found are loaded from the store again, so
+//! messages deleted in other ways, like the replies of deleted threads, are
+//! never shown to bots.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use crate::{ai::Source, models::Message, store::ChatStore};
+
+/// How many messages are retrieved for every question if
+/// `AI_ARCHIVE_RESULTS` isn't set
+const DEFAULT_RESULTS: usize = 5;
+/// How much of every message bots are shown
+const MAX_SOURCE_CHARS: usize = 500;
+/// BM25 parameters, the usual ones
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+/// Words too common to tell messages apart
+const STOP_WORDS: &[&str] = &[
+    "a", "about", "an", "and", "are", "as", "at", "be", "but", "by", "can", "did", "do", "does",
+    "for", "from", "had", "has", "have", "he", "her", "his", "how", "i", "if", "in", "is", "it",
+    "its", "me", "my", "of", "on", "or", "our", "she", "so", "that", "the", "their", "them",
+    "there", "they", "this", "to", "us", "was", "we", "were", "what", "when", "where", "which",
+    "who", "why", "will", "with", "would", "you", "your",
+];
+
+#[derive(Default)]
+struct Document {
+    /// How often every term appears in the message
+    terms: HashMap<String, u32>,
+    /// How many terms the message has
+    len: u32,
+}
+
+#[derive(Default)]
+struct Index {
+    documents: HashMap<i32, Document>,
+    /// The messages every term appears in
+    postings: HashMap<String, HashSet<i32>>,
+    total_len: u64,
+}
+
+pub struct ArchiveIndex {
+    /// How many messages are retrieved for every question, 0 for none
+    results: usize,
+    index: RwLock<Index>,
+}
+
+impl ArchiveIndex {
+    /// Index every public message in the store. Nothing is indexed if
+    /// `AI_ARCHIVE_RESULTS` turns retrieval off.
+    pub async fn build(store: &dyn ChatStore) -> sqlx::Result<Self> {
+        let results = std::env::var("AI_ARCHIVE_RESULTS")
+            .ok()
+            .and_then(|results| results.parse().ok())
+            .unwrap_or(DEFAULT_RESULTS);
+        let archive = Self {
+            results,
+            index: RwLock::default(),
+        };
+        archive.rebuild(store).await?;
+        Ok(archive)
+    }
+
+    /// Index every public message in the store again, like after an import
+    pub async fn rebuild(&self, store: &dyn ChatStore) -> sqlx::Result<()> {
+        if self.results == 0 {
+            return Ok(());
+        }
+        let mut index = Index::default();
+        let rooms = store.rooms().await?;
+        let places = std::iter::once(None).chain(rooms.iter().map(|room| Some(room.id)));
+        for room in places {
+            for thread in store.get_threads(room).await? {
+                if let Some(id) = thread.id {
+                    for reply in store.get_replies(id).await? {
+                        index.add(&reply);
+                    }
+                }
+                index.add(&thread);
+            }
+        }
+        log::info!(
+            "Indexed {} messages for bots to search",
+            index.documents.len()
+        );
+        *self.index.write().unwrap() = index;
+        Ok(())
+    }
+
+    /// Index a new or edited message
+    pub fn add(&self, message: &Message) {
+        if self.results > 0 {
+            self.index.write().unwrap().add(message);
+        }
+    }
+
+    pub fn remove(&self, id: i32) {
+        self.index.write().unwrap().remove(id);
+    }
+
+    /// Get the messages most relevant to a question, best first, leaving out
+    /// the message asking it
+    pub async fn retrieve(
+        &self,
+        store: &dyn ChatStore,
+        question: &str,
+        query_id: Option<i32>,
+    ) -> Vec<Source> {
+        if self.results == 0 {
+            return vec![];
+        }
+        let ids = self
+            .index
+            .read()
+            .unwrap()
+            .search(question, self.results + 1);
+        let mut sources = vec![];
+        for id in ids.into_iter().filter(|&id| Some(id) != query_id) {
+            match store.get_message(id).await {
+                Ok(Some(message)) if message.recipient.is_none() => sources.push(Source {
+                    id,
+                    sender: message.sender,
+                    sent_date: message.sent_date,
+                    contents: shorten(&message.source),
+                }),
+                Ok(Some(_)) => {}
+                // Deleted by a retention policy or along with its thread
+                Ok(None) => self.remove(id),
+                Err(e) => log::error!("Failed to load message {id} for bot sources:\n{e}"),
+            }
+            if sources.len() == self.results {
+                break;
+            }
+        }
+        sources
+    }
+}
+
+impl Index {
+    fn add(&mut self, message: &Message) {
+        let Some(id) = message.id else {
+            return;
+        };
+        self.remove(id);
+        // Direct messages are private and commands are noise
+        if message.recipient.is_some() || message.source.starts_with('!') {
+            return;
+        }
+        let mut document = Document::default();
+        for term in terms(&message.source) {
+            *document.terms.entry(term).or_default() += 1;
+            document.len += 1;
+        }
+        if document.len == 0 {
+            return;
+        }
+        for term in document.terms.keys() {
+            self.postings.entry(term.clone()).or_default().insert(id);
+        }
+        self.total_len += u64::from(document.len);
+        self.documents.insert(id, document);
+    }
+
+    fn remove(&mut self, id: i32) {
+        let Some(document) = self.documents.remove(&id) else {
+            return;
+        };
+        for term in document.terms.keys() {
+            if let Some(postings) = self.postings.get_mut(term) {
+                postings.remove(&id);
+                if postings.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.total_len -= u64::from(document.len);
+    }
+
+    /// Get the best matches for a query by their BM25 score
+    fn search(&self, query: &str, limit: usize) -> Vec<i32> {
+        if self.documents.is_empty() {
+            return vec![];
+        }
+        let count = self.documents.len() as f64;
+        let average_len = self.total_len as f64 / count;
+        let mut scores: HashMap<i32, f64> = HashMap::new();
+        for term in terms(query).collect::<HashSet<_>>() {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let frequency = postings.len() as f64;
+            let idf = (1.0 + (count - frequency + 0.5) / (frequency + 0.5)).ln();
+            for id in postings {
+                let document = &self.documents[id];
+                let tf = f64::from(document.terms[&term]);
+                let norm = 1.0 - B + B * f64::from(document.len) / average_len;
+                *scores.entry(*id).or_default() += idf * tf * (K1 + 1.0) / (tf + K1 * norm);
+            }
+        }
+        let mut scores = scores.into_iter().collect::<Vec<_>>();
+        // Newer messages win ties
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+        scores.into_iter().take(limit).map(|(id, _)| id).collect()
+    }
+}
+
+/// Split text into the lowercase words that are indexed
+fn terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+}
+
+fn shorten(source: &str) -> String {
+    let text = source.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= MAX_SOURCE_CHARS {
+        return text;
+    }
+    let mut short = text.chars().take(MAX_SOURCE_CHARS - 1).collect::<String>();
+    short.push('…');
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn message(id: Option<i32>, source: &str) -> Message {
+        Message {
+            id,
+            parent_id: None,
+            sender: "Alice".to_string(),
+            recipient: None,
+            sent_date: Utc::now(),
+            edited_at: None,
+            contents: source.to_string(),
+            source: source.to_string(),
+            pinned: false,
+            hidden: false,
+            session: None,
+            should_notify: false,
+            reply_count: 0,
+            reactions: vec![],
+            attachments: vec![],
+            previews: vec![],
+        }
+    }
+
+    fn index(sources: &[&str]) -> Index {
+        let mut index = Index::default();
+        for (id, source) in (1..).zip(sources) {
+            index.add(&message(Some(id), source));
+        }
+        index
+    }
+
+    #[test]
+    fn splits_text_into_terms() {
+        let found = terms("Is the Rust-lang launch at 9, or isn't it? x").collect::<Vec<_>>();
+        assert_eq!(found, ["rust", "lang", "launch", "isn"]);
+    }
+
+    #[test]
+    fn ranks_rare_terms_higher() {
+        let index = index(&[
+            "rocket launch today",
+            "rocket engines are loud",
+            "rocket fuel and rocket engines",
+            "telescope images",
+        ]);
+        assert_eq!(index.search("telescope", 10), [4]);
+        // Only one message has a launch, which outweighs the common rockets
+        assert_eq!(index.search("rocket launch", 10)[0], 1);
+        assert_eq!(index.search("engines", 10).len(), 2);
+        assert!(index.search("submarine", 10).is_empty());
+        assert!(index.search("the and", 10).is_empty());
+    }
+
+    #[test]
+    fn ranks_shorter_and_newer_messages_first() {
+        let index = index(&[
+            "launch",
+            "launch window opens after the storm passes over the coast",
+            "launch",
+        ]);
+        assert_eq!(index.search("launch", 10), [3, 1, 2]);
+        assert_eq!(index.search("launch", 1), [3]);
+    }
+
+    #[test]
+    fn keeps_commands_and_direct_messages_out() {
+        let mut index = index(&["!ask bob about the launch"]);
+        index.add(&Message {
+            recipient: Some("bob".to_string()),
+            ..message(Some(2), "secret launch")
+        });
+        index.add(&message(None, "unsaved launch"));
+        assert!(index.search("launch", 10).is_empty());
+        assert!(index.documents.is_empty());
+    }
+
+    #[test]
+    fn replaces_edited_messages_and_removes_deleted_ones() {
+        let mut index = index(&["apples", "apples and pears"]);
+        index.add(&message(Some(1), "plums"));
+        assert_eq!(index.search("apples", 10), [2]);
+        assert_eq!(index.search("plums", 10), [1]);
+        index.remove(2);
+        index.remove(1);
+        assert!(index.search("apples", 10).is_empty());
+        assert!(index.documents.is_empty());
+        assert!(index.postings.is_empty());
+        assert_eq!(index.total_len, 0);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_from_the_store() {
+        let store = MemoryStore::default();
+        let thread = store
+            .insert_message(&message(None, "when is the launch"))
+            .await
+            .unwrap();
+        let reply = store
+            .insert_message(&Message {
+                parent_id: Some(thread),
+                ..message(None, "the launch is at noon")
+            })
+            .await
+            .unwrap();
+        store
+            .insert_message(&Message {
+                recipient: Some("bob".to_string()),
+                ..message(None, "a secret launch")
+            })
+            .await
+            .unwrap();
+        let archive = ArchiveIndex {
+            results: 5,
+            index: RwLock::default(),
+        };
+        archive.rebuild(&store).await.unwrap();
+
+        let ids = |sources: Vec<Source>| sources.iter().map(|source| source.id).collect::<Vec<_>>();
+        let found = archive.retrieve(&store, "launch noon", None).await;
+        assert_eq!(ids(found), [reply, thread]);
+        let found = archive.retrieve(&store, "launch noon", Some(reply)).await;
+        assert_eq!(ids(found), [thread]);
+
+        // Messages saved without being added are only found after a rebuild
+        let later = store
+            .insert_message(&message(None, "launch moved to monday"))
+            .await
+            .unwrap();
+        assert!(archive.retrieve(&store, "monday", None).await.is_empty());
+        archive.rebuild(&store).await.unwrap();
+        assert_eq!(ids(archive.retrieve(&store, "monday", None).await), [later]);
+
+        // Messages deleted from the store are dropped when they are found
+        store.delete_message(thread).await.unwrap();
+        assert!(archive.retrieve(&store, "noon", None).await.is_empty());
+        let index = archive.index.read().unwrap();
+        assert!(!index.documents.contains_key(&reply));
+        assert!(index.documents.contains_key(&later));
+    }
+}
@@ -1,33 +1,169 @@
 use std::sync::{Arc, Mutex};
 
-use crate::{ai::AiContext, models::Message, routes};
+use crate::{
+    ai::{self, AiContext, BotRoster},
+    attachments::{AttachmentLimits, FileStorage},
+    models::ChatEvent,
+    names::NamePolicy,
+    presence::Presence,
+    ratelimit::RateLimits,
+    retention,
+    retrieval::ArchiveIndex,
+    routes,
+    screening::Screening,
+    store::ChatStore,
+    tools::Reminders,
+    triggers::Cooldowns,
+    unfurl::UnfurlConfig,
+    usage::Quotas,
+};
 use axum::{
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    routing::{delete, get, post},
     Extension, Router,
 };
 use tokio::sync::broadcast::{channel, Sender};
 use tower_http::services::ServeDir;
-pub type RoomsStream = Sender<Message>;
+pub type RoomsStream = Sender<ChatEvent>;
+
+/// The largest archive that can be imported at `/admin/import`
+const MAX_ARCHIVE_BYTES: usize = 64 * 1024 * 1024;
 
 #[derive(Clone)]
 pub struct AppState {
     pub ai_context: Arc<Mutex<AiContext>>,
+    /// The bots as they are configured, which can be read while a bot is
+    /// answering
+    pub bots: Arc<BotRoster>,
+    pub store: Arc<dyn ChatStore>,
+    /// Where the files attached to messages are kept
+    pub files: Arc<dyn FileStorage>,
+    pub attachment_limits: Arc<AttachmentLimits>,
+    pub unfurl: Arc<UnfurlConfig>,
+    pub names: Arc<NamePolicy>,
+    pub rate_limits: Arc<RateLimits>,
+    /// The daily quotas on the tokens bots use
+    pub ai_quotas: Arc<Quotas>,
+    pub presence: Arc<Presence>,
+    /// When bots last answered in each conversation on their own
+    pub bot_cooldowns: Arc<Cooldowns>,
+    /// The index bots find archived messages relevant to questions in
+    pub archive_index: Arc<ArchiveIndex>,
+    /// What decides which messages admins need to review
+    pub screening: Arc<Screening>,
+    /// The reminders bots scheduled that haven't been posted yet
+    pub reminders: Arc<Reminders>,
+    /// The token users need to enter at `/admin` to become admins. Nobody can
+    /// become an admin if this is not set.
+    pub admin_token: Option<String>,
+    /// The token users need to enter at `/admin` to become moderators, who
+    /// can mute, kick and ban users
+    pub moderator_token: Option<String>,
 }
 
-pub async fn init_router(groq_api_key: String) -> Router {
-    let (tx, _rx) = channel::<Message>(10);
+pub async fn init_router(
+    groq_api_key: String,
+    admin_token: Option<String>,
+    moderator_token: Option<String>,
+    store: Arc<dyn ChatStore>,
+    files: Arc<dyn FileStorage>,
+) -> Router {
+    let (tx, _rx) = channel::<ChatEvent>(10);
+
+    let bots = store.bots().await.expect("Failed to load bots");
+    let bots = if bots.is_empty() {
+        let bots = ai::initial_bots();
+        for bot in &bots {
+            if let Err(e) = store.save_bot(bot).await {
+                log::error!("Failed to save bot {}:\n{e}", bot.name());
+            }
+        }
+        bots
+    } else {
+        bots
+    };
 
     let serve_assets = ServeDir::new("assets");
     // let groq_client = AsyncGroqClient::new(groq_api_key, None).await;
-    let ai_context = Arc::new(Mutex::new(AiContext::new(&groq_api_key).unwrap()));
+    let ai_context = AiContext::new(&groq_api_key, bots).unwrap();
+    let bots = ai_context.roster();
+    let screening = Arc::new(Screening::from_env(&ai_context));
+    let ai_context = Arc::new(Mutex::new(ai_context));
+    let attachment_limits = Arc::new(AttachmentLimits::from_env());
+    let unfurl = Arc::new(UnfurlConfig::from_env());
+    let names = Arc::new(NamePolicy::from_env());
+    let rate_limits = Arc::new(RateLimits::from_env());
+    let ai_quotas = Arc::new(Quotas::from_env());
+    let archive_index = Arc::new(
+        ArchiveIndex::build(store.as_ref())
+            .await
+            .expect("Failed to index messages"),
+    );
+    retention::spawn_pruning(
+        store.clone(),
+        files.clone(),
+        archive_index.clone(),
+        tx.clone(),
+    );
 
     Router::new()
         .route("/", get(routes::home))
         .route("/feed", get(routes::feed))
         .route("/stream", get(routes::handle_stream))
         .route("/setname", post(routes::set_name))
-        .route("/send", post(routes::send_message))
+        .route(
+            "/send",
+            post(routes::send_message).layer(DefaultBodyLimit::max(attachment_limits.body_limit())),
+        )
+        .route("/messages/:id", delete(routes::delete_message))
+        .route("/messages/:id/edit", post(routes::edit_message))
+        .route("/messages/:id/reactions", post(routes::toggle_reaction))
+        .route("/messages/:id/pin", post(routes::toggle_pin))
+        .route("/messages/:id/thread", get(routes::thread))
+        .route("/attachments/:key", get(routes::attachment))
+        .route(
+            "/attachments/:key/thumbnail",
+            get(routes::attachment_thumbnail),
+        )
+        .route("/dm/:user", get(routes::direct_messages))
+        .route("/search", get(routes::search))
+        .route("/admin", get(routes::admin).post(routes::admin_sign_in))
+        .route("/admin/archives", get(routes::archives))
+        .route("/admin/export", get(routes::export_archive))
+        .route(
+            "/admin/import",
+            post(routes::import_archive).layer(DefaultBodyLimit::max(MAX_ARCHIVE_BYTES)),
+        )
+        .route(
+            "/admin/retention",
+            get(routes::retention).post(routes::set_retention),
+        )
+        .route("/admin/moderation", get(routes::moderation))
+        .route("/admin/review", get(routes::review))
+        .route("/admin/review/policy", post(routes::set_screen_policy))
+        .route("/admin/review/release", post(routes::release_review))
+        .route("/admin/review/delete", post(routes::delete_review))
+        .route("/admin/usage", get(routes::usage_page))
+        .route("/admin/moderation/lift", post(routes::lift_sanction))
+        .route("/highlight.css", get(routes::highlight_css))
         .fallback_service(serve_assets)
         .layer(Extension(tx))
-        .with_state(AppState { ai_context })
+        .with_state(AppState {
+            ai_context,
+            bots,
+            store,
+            files,
+            attachment_limits,
+            unfurl,
+            names,
+            rate_limits,
+            ai_quotas,
+            presence: Arc::default(),
+            bot_cooldowns: Arc::default(),
+            archive_index,
+            screening,
+            reminders: Arc::default(),
+            admin_token,
+            moderator_token,
+        })
 }
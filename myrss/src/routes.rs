@@ -1,41 +1,88 @@
 use axum::{
-    extract::State,
-    http::{HeaderValue, StatusCode},
-    response::{sse::Event, IntoResponse, Redirect, Sse},
+    async_trait,
+    extract::{FromRequest, Multipart, Path, Query, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{sse::Event, IntoResponse, Redirect, Response, Sse},
     Extension, Form,
 };
 use axum_extra::extract::cookie::{Cookie, CookieJar};
-use chrono::Utc;
-use futures::Stream;
-use serde::Deserialize;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use futures::{future::BoxFuture, FutureExt, Stream};
+use serde::{Deserialize, Deserializer};
 use serde_json::json;
 use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
-use tokio::{runtime::Handle, sync::broadcast::Sender};
+use tokio::sync::broadcast::Sender;
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tokio_stream::StreamExt as _;
 
 use crate::{
-    ai::Bot,
-    models::{Message, MessageNew},
+    ai::{Bot, BotSettings, ContextMessage, Conversation},
+    archive,
+    attachments::{self, Upload, UploadError},
+    errors::ApiError,
+    highlight,
+    models::{
+        user_key, ChatEvent, Message, MessageNew, ModerationAction, RetentionPolicy, Review,
+        SanctionKind, ScreenAction, ScreenPolicy,
+    },
+    moderation::{self, Moderation, Role},
+    names::NameError,
+    presence::Presence,
+    ratelimit::{self, ClientIp},
+    render, retention,
     router::AppState,
-    templates::MessageTemplate,
+    store::{ChatStore, SearchFilter},
+    templates::{
+        ArchiveAdminTemplate, DirectMessagesTemplate, MessageTemplate, ModerationTemplate,
+        PreviewsTemplate, ReactionsTemplate, RetentionTemplate, ReviewTemplate, SearchTemplate,
+        ThreadTemplate, UsageTemplate,
+    },
+    tools::ChatTools,
+    triggers::{Incoming, MAX_BOT_CHAIN},
+    unfurl, usage,
 };
 use crate::{router::RoomsStream, templates};
 
 const HELP_MESSAGE: &str = "Valid commands:
 - !ai &lt;message&gt; - ask a question to the default bot (greg)
 - !ask &lt;bot&gt; &lt;message&gt; - ask a question to a bot by name
-- !newbot &lt;name&gt; [lang:&lt;language&gt;] &lt;instructions&gt; - create a new
-bot that follows custom instructions
+- @&lt;bot&gt; - mention a bot anywhere in a message to ask it the message
+- !newbot &lt;name&gt; [lang=&lt;language&gt;] [model=&lt;model&gt;]
+[temperature=&lt;0-2&gt;] [max_tokens=&lt;n&gt;] [provider=&lt;provider&gt;]
+&lt;instructions&gt; - create a new bot that follows custom instructions
+- !editbot &lt;name&gt; [settings] [instructions] - change the settings of a
+bot you created, and its instructions if given. Bots also take mention=on|off,
+pattern=&lt;regex&gt;|off, chance=[room:]&lt;0-1&gt; and cooldown=&lt;duration&gt; to
+answer messages on their own, and room_context=&lt;n&gt; to be shown the latest
+messages of the room
 - !listbots - list bots by name
 - !removebot <bot> - remove a bot (you can only remove a bot you created)
+- !msg &lt;user&gt; &lt;message&gt; - send a direct message to a user
+- !search &lt;terms&gt; - search the chat history
 - !online - ask how many users are online
-- !help - show this message";
+- !usage - show how many tokens your questions to bots used
+- !help - show this message
+
+Moderators can also use:
+- !mute &lt;user&gt; [duration] [reason] - keep a user from sending messages, for a
+duration like 10m, 2h or 7d or until unmuted
+- !unmute &lt;user&gt; - let a muted user send messages again
+- !kick &lt;user&gt; [reason] - disconnect a user
+- !ban &lt;user&gt; [duration] [reason] - disconnect a user and keep them out
+- !unban &lt;user&gt; - let a banned user back in";
 
 const BOT_RESPONSES_NOTIFY: bool = false;
 
+const MAX_SEARCH_RESULTS: i64 = 50;
+/// The number of results `!search` lists in the chat
+const MAX_COMMAND_SEARCH_RESULTS: i64 = 5;
+/// What hidden messages show until an admin reviews them
+const HIDDEN_MESSAGE: &str = "*This message is hidden until an admin reviews it.*";
+
 pub async fn home(jar: CookieJar) -> impl IntoResponse {
     if jar.get("sender-name").is_some() {
         return Redirect::to("/feed").into_response();
@@ -48,31 +95,86 @@ pub struct NamePayload {
     name: String,
 }
 
-pub async fn set_name(jar: CookieJar, Form(payload): Form<NamePayload>) -> impl IntoResponse {
+pub async fn set_name(
+    state: State<AppState>,
+    jar: CookieJar,
+    Form(payload): Form<NamePayload>,
+) -> impl IntoResponse {
     // let headers = AppendHeaders([(SET_COOKIE, format!("sender-name={}", payload.name))]);
     let name = payload.name.trim();
-    if name.to_lowercase() == "system" || name.ends_with(" (Bot)") {
-        // If the user tries to pretend to be the System, don't let them
-        // Also don't let them impersonate bots
-        return (
-            StatusCode::BAD_REQUEST,
-            templates::BannedName {
-                name: name.to_string(),
-            },
-        )
-            .into_response();
+    if let Err(e) = check_name(&state, name) {
+        return banned_name(name, e).into_response();
+    }
+    let jar = with_session(jar.add(Cookie::new("sender-name", name.to_string())));
+    match owns_name(&state, &jar, name).await {
+        Ok(true) => {}
+        Ok(false) => return banned_name(name, NameError::Taken).into_response(),
+        Err(e) => return ApiError::from(e).into_response(),
     }
-    let jar = jar.add(Cookie::new("sender-name", payload.name));
     (jar, Redirect::to("/feed")).into_response()
 }
 
+/// The session a request is made from. Unlike the name cookie, which can be
+/// set to any name, sessions are only issued by the server.
+fn session(jar: &CookieJar) -> Option<String> {
+    jar.get("session-id")
+        .map(|session| session.value().to_string())
+}
+
+/// Whether the session of a request has the name the request is made under.
+/// A name belongs to the first session that uses it, so that nobody else can
+/// read or send the direct messages of its user.
+async fn owns_name(state: &AppState, jar: &CookieJar, name: &str) -> sqlx::Result<bool> {
+    match session(jar) {
+        Some(session) => state.store.claim_name(name, &session).await,
+        None => Ok(false),
+    }
+}
+
+/// Issue a session to a browser that doesn't have one yet
+fn with_session(jar: CookieJar) -> CookieJar {
+    if jar.get("session-id").is_some() {
+        return jar;
+    }
+    let session = uuid::Uuid::new_v4().simple().to_string();
+    jar.add(
+        Cookie::build(("session-id", session))
+            .path("/")
+            .http_only(true)
+            .permanent(),
+    )
+}
+
+/// Check a name against the name policy. This is needed wherever a request
+/// is made under a name, since the cookie it is kept in can be set without
+/// going through `set_name`.
+fn check_name(state: &AppState, name: &str) -> Result<(), NameError> {
+    let bots = state.bots.names();
+    state.names.check(name, bots.iter().map(String::as_str))
+}
+
+fn banned_name(name: &str, error: NameError) -> impl IntoResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        templates::BannedName {
+            name: name.to_string(),
+            reason: error.to_string(),
+        },
+    )
+}
+
+/// The events sent to one connection, which end after the user is kicked
 struct StreamWrapper(
     String,
-    tokio::sync::broadcast::Sender<Message>,
-    BroadcastStream<Message>,
+    tokio::sync::broadcast::Sender<ChatEvent>,
+    BroadcastStream<ChatEvent>,
+    /// Whether the user was kicked
+    bool,
+    Arc<Presence>,
 );
 impl Drop for StreamWrapper {
     fn drop(&mut self) {
+        self.4.disconnect(&self.0);
         let num_receivers = self.1.receiver_count();
         send_message_backend(
             self.1.clone(),
@@ -86,17 +188,26 @@ impl Drop for StreamWrapper {
 }
 
 impl Stream for StreamWrapper {
-    type Item = Result<Message, BroadcastStreamRecvError>;
+    type Item = Result<ChatEvent, BroadcastStreamRecvError>;
     fn poll_next(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        let inner_stream = unsafe { self.map_unchecked_mut(|wrapper| &mut wrapper.2) };
-        inner_stream.poll_next(cx)
+        let wrapper = self.get_mut();
+        if wrapper.3 {
+            return std::task::Poll::Ready(None);
+        }
+        let poll = std::pin::Pin::new(&mut wrapper.2).poll_next(cx);
+        // The kick itself is still sent, so the client knows not to reconnect
+        if let std::task::Poll::Ready(Some(Ok(ChatEvent::Kick { user, .. }))) = &poll {
+            wrapper.3 = *user == user_key(&wrapper.0);
+        }
+        poll
     }
 }
 
 pub async fn handle_stream(
+    state: State<AppState>,
     jar: CookieJar,
     // State(count): State<Arc<Mutex<u32>>>,
     Extension(tx): Extension<RoomsStream>,
@@ -104,6 +215,9 @@ pub async fn handle_stream(
     let Some(name) = jar.get("sender-name") else {
         return Redirect::to("/").into_response();
     };
+    if check_name(&state, name.value()).is_err() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
     let Some(tz) = jar.get("timezone") else {
         return StatusCode::BAD_REQUEST.into_response();
     };
@@ -111,27 +225,162 @@ pub async fn handle_stream(
         return (jar.remove("timezone"), Redirect::to("/")).into_response();
     };
     let name = name.value().to_string();
+    let admin = is_admin(&state, &jar);
+    match owns_name(&state, &jar, &name).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+        Err(e) => return ApiError::from(e).into_response(),
+    }
+    let session = session(&jar);
+    match moderation::blocking_sanction(state.store.as_ref(), &name).await {
+        Ok(Some(sanction)) if sanction.kind == SanctionKind::Ban => {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        Ok(_) => {}
+        Err(e) => return ApiError::from(e).into_response(),
+    }
+    if let Err(e) = state.store.record_user(&name).await {
+        log::error!("Failed to record user {name}:\n{e}");
+    }
 
     let rx = tx.subscribe();
-    let stream = StreamWrapper(name.clone(), tx.clone(), BroadcastStream::new(rx));
-
-    let sse = Sse::new(stream.filter_map(|msg| msg.ok()).map(move |msg| {
-        let sname = msg.sender.clone();
-        let preview = if msg.contents.len() <= 40 {
-            msg.contents.clone()
-        } else {
-            format!("{}...", msg.contents.chars().take(37).collect::<String>())
-        };
-        let should_notify = msg.should_notify;
-        let msghtml = MessageTemplate { message: msg, tz }.to_string();
-        let data = json!({
-            "sender": sname,
-            "message": msghtml,
-            "preview": preview,
-            "notify": should_notify,
-        });
-        Result::<_, Infallible>::Ok(Event::default().data(data.to_string()))
-    }))
+    state.presence.connect(&name);
+    let stream = StreamWrapper(
+        name.clone(),
+        tx.clone(),
+        BroadcastStream::new(rx),
+        false,
+        state.presence.clone(),
+    );
+    let joined = name.clone();
+
+    let viewer = name.clone();
+    let viewer_key = user_key(&viewer);
+    let sse = Sse::new(
+        stream
+            .filter_map(move |event| event.ok().filter(|event| event.is_visible_to(&name)))
+            .map(move |event| {
+                let data = match event {
+                    ChatEvent::Message(msg) => {
+                        let sname = msg.sender.clone();
+                        let preview = if msg.contents.len() <= 40 {
+                            msg.contents.clone()
+                        } else {
+                            format!("{}...", msg.contents.chars().take(37).collect::<String>())
+                        };
+                        // The other user in the conversation if this is a direct
+                        // message
+                        let dm = msg.recipient.as_ref().map(|recipient| {
+                            if *recipient == viewer_key {
+                                user_key(&msg.sender)
+                            } else {
+                                recipient.clone()
+                            }
+                        });
+                        // Only the users mentioned in a message and the recipients of
+                        // direct messages get notified about them
+                        let mentioned = msg.should_notify
+                            && (msg.recipient.as_ref() == Some(&viewer_key)
+                                || render::mentions(&msg.contents)
+                                    .into_iter()
+                                    .any(|mention| render::mention_matches(mention, &viewer)));
+                        let parent = msg.parent_id;
+                        let can_modify = admin || msg.is_sent_from(session.as_deref());
+                        let msghtml = MessageTemplate {
+                            message: msg,
+                            tz,
+                            viewer: viewer.clone(),
+                            can_modify,
+                            admin,
+                        }
+                        .to_string();
+                        json!({
+                            "kind": "message",
+                            "parent": parent,
+                            "dm": dm,
+                            "sender": sname,
+                            "message": msghtml,
+                            "preview": preview,
+                            "mentioned": mentioned,
+                        })
+                    }
+                    ChatEvent::Edit(msg) => {
+                        let id = msg.id;
+                        let can_modify = admin || msg.is_sent_from(session.as_deref());
+                        let msghtml = MessageTemplate {
+                            message: msg,
+                            tz,
+                            viewer: viewer.clone(),
+                            can_modify,
+                            admin,
+                        }
+                        .to_string();
+                        json!({
+                            "kind": "edit",
+                            "id": id,
+                            "message": msghtml,
+                        })
+                    }
+                    ChatEvent::Delete(id) => json!({
+                        "kind": "delete",
+                        "id": id,
+                    }),
+                    ChatEvent::Replies { message, count } => json!({
+                        "kind": "replies",
+                        "id": message,
+                        "label": templates::replies_label(&count),
+                    }),
+                    ChatEvent::Reactions {
+                        message, reactions, ..
+                    } => {
+                        let html = ReactionsTemplate {
+                            id: message,
+                            reactions,
+                            viewer: viewer.clone(),
+                        }
+                        .to_string();
+                        json!({
+                            "kind": "reactions",
+                            "id": message,
+                            "reactions": html,
+                        })
+                    }
+                    ChatEvent::Notice { message, .. } => {
+                        let msghtml = MessageTemplate {
+                            message,
+                            tz,
+                            viewer: viewer.clone(),
+                            can_modify: false,
+                            admin,
+                        }
+                        .to_string();
+                        json!({
+                            "kind": "notice",
+                            "message": msghtml,
+                        })
+                    }
+                    ChatEvent::Kick { reason, .. } => json!({
+                        "kind": "kicked",
+                        "reason": reason,
+                    }),
+                    ChatEvent::Previews {
+                        message, previews, ..
+                    } => {
+                        let html = PreviewsTemplate {
+                            id: message,
+                            previews,
+                        }
+                        .to_string();
+                        json!({
+                            "kind": "previews",
+                            "id": message,
+                            "previews": html,
+                        })
+                    }
+                };
+                Result::<_, Infallible>::Ok(Event::default().data(data.to_string()))
+            }),
+    )
     .keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(10))
@@ -141,7 +390,7 @@ pub async fn handle_stream(
     send_message_backend(
         tx,
         construct_message(
-            format!("{name} joined. Users currently online: {num_receivers}",),
+            format!("{joined} joined. Users currently online: {num_receivers}",),
             "System",
             true,
         ),
@@ -152,11 +401,93 @@ pub async fn handle_stream(
     resp
 }
 
+/// A message as it is sent from the compose form, which is sent as multipart
+/// form data so that files can be attached
+pub struct MessageUpload {
+    message: MessageNew,
+    files: Vec<Upload>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for MessageUpload {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_multipart = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("multipart/form-data"));
+        if !is_multipart {
+            let Form(message) = Form::<MessageNew>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self {
+                message,
+                files: vec![],
+            });
+        }
+
+        let mut multipart = Multipart::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let mut message = MessageNew {
+            contents: String::new(),
+            parent_id: None,
+            recipient: None,
+        };
+        let mut files = vec![];
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(IntoResponse::into_response)?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+            if name == "files" {
+                let filename = field.file_name().map(str::to_string);
+                let content_type = field.content_type().map(str::to_string);
+                let data = field.bytes().await.map_err(IntoResponse::into_response)?;
+                // Browsers send an empty file when none was picked
+                if filename.as_deref().unwrap_or_default().is_empty() && data.is_empty() {
+                    continue;
+                }
+                if files.len() == attachments::MAX_FILES {
+                    return Err(UploadError::TooMany.into_response());
+                }
+                files.push(Upload::new(
+                    filename.as_deref(),
+                    content_type.as_deref(),
+                    data,
+                ));
+                continue;
+            }
+            let value = field.text().await.map_err(IntoResponse::into_response)?;
+            match name.as_str() {
+                "contents" => message.contents = value,
+                "parent_id" if !value.is_empty() => {
+                    message.parent_id = Some(
+                        value
+                            .parse()
+                            .map_err(|_| ApiError::BadRequest.into_response())?,
+                    );
+                }
+                "recipient" if !value.is_empty() => message.recipient = Some(value),
+                _ => {}
+            }
+        }
+        Ok(Self { message, files })
+    }
+}
+
 pub async fn send_message(
     state: State<AppState>,
     Extension(tx): Extension<RoomsStream>,
     jar: CookieJar,
-    Form(form): Form<MessageNew>,
+    ClientIp(ip): ClientIp,
+    MessageUpload {
+        message: form,
+        files,
+    }: MessageUpload,
 ) -> impl IntoResponse {
     let Some(sender) = jar.get("sender-name") else {
         return Redirect::to("/").into_response();
@@ -167,19 +498,213 @@ pub async fn send_message(
     let Ok(tz) = tz.value().to_string().parse::<i32>() else {
         return (jar.remove("timezone"), Redirect::to("/")).into_response();
     };
-    let message_command = parse_message_command(&form.contents);
+    if form.contents.trim().is_empty() && files.is_empty() {
+        return ApiError::BadRequest.into_response();
+    }
+    if let Err(e) = check_name(&state, sender.value()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("You can't use this name. {e}"),
+        )
+            .into_response();
+    }
+    match owns_name(&state, &jar, sender.value()).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+        Err(e) => return ApiError::from(e).into_response(),
+    }
+    match moderation::blocking_sanction(state.store.as_ref(), sender.value()).await {
+        Ok(Some(sanction)) => {
+            send_notice(
+                &tx,
+                sender.value(),
+                moderation::describe_sanction(&sanction),
+            );
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        Ok(None) => {}
+        Err(e) => return ApiError::from(e).into_response(),
+    }
+    if let Err(wait) = state
+        .rate_limits
+        .messages
+        .take(&user_key(sender.value()), ip)
+    {
+        send_notice(
+            &tx,
+            sender.value(),
+            format!(
+                "You are sending messages too quickly. Try again in {}.",
+                ratelimit::format_wait(wait)
+            ),
+        );
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+    // Every file is checked before anything is sent, so that a message is
+    // never sent without some of its files
+    let uploads = match files
+        .into_iter()
+        .map(|upload| {
+            let content_type = state.attachment_limits.check(&upload)?;
+            Ok((upload, content_type))
+        })
+        .collect::<Result<Vec<_>, UploadError>>()
+    {
+        Ok(uploads) => uploads,
+        Err(e) => return e.into_response(),
+    };
+    // Direct messages are never commands, except for `!msg` which sends one
+    let (message_command, recipient, contents) =
+        match (form.recipient, parse_message_command(&form.contents)) {
+            (Some(recipient), _) => (None, Some(user_key(&recipient)), form.contents.clone()),
+            (
+                None,
+                Some(Ok(MessageCommand::DirectMessage {
+                    recipient,
+                    contents,
+                })),
+            ) => (None, Some(user_key(&recipient)), contents),
+            (None, command) => (command, None, form.contents.clone()),
+        };
     let is_command = message_command.is_some();
 
+    // Direct messages don't have threads
+    let parent_id = match form.parent_id.filter(|_| recipient.is_none()) {
+        Some(parent_id) => match state.store.get_message(parent_id).await {
+            // Replies to replies go in the same thread as the message they
+            // reply to
+            Ok(Some(parent)) if parent.recipient.is_none() => {
+                Some(parent.parent_id.unwrap_or(parent_id))
+            }
+            Ok(_) => return ApiError::DoesNotExist.into_response(),
+            Err(e) => return ApiError::from(e).into_response(),
+        },
+        None => None,
+    };
+
     let sender = sender.value().to_string();
-    let message = construct_message(form.contents.clone(), sender.clone(), !is_command);
+    let sender_name = sender.clone();
+    let admin = is_admin(&state, &jar);
+    let role = role(&state, &jar);
+
+    // Public messages are screened before anyone sees them, commands too
+    // since they are posted like other messages. Commands of admins and
+    // moderators aren't, so that the reasons they give for sanctions don't
+    // hold them up.
+    let screened = match (&recipient, is_command && role.is_some()) {
+        (None, false) => screen_message(&state, parent_id, &contents).await,
+        _ => None,
+    };
+    if let Some((ScreenAction::Hold, reason)) = &screened {
+        let review = Review {
+            id: 0,
+            message: None,
+            action: ScreenAction::Hold,
+            reason: reason.clone(),
+            sender: sender.clone(),
+            parent_id,
+            source: contents.clone(),
+            created_at: Utc::now(),
+            session: session(&jar),
+        };
+        if let Err(e) = state.store.add_review(&review).await {
+            return ApiError::from(e).into_response();
+        }
+        let mut notice = "Your message is held until an admin reviews it.".to_string();
+        if !uploads.is_empty() {
+            notice.push_str(" Its attachments weren't kept.");
+        }
+        send_notice(&tx, &sender, notice);
+        return StatusCode::ACCEPTED.into_response();
+    }
+    let hidden = matches!(screened, Some((ScreenAction::Hide, _)));
+    let shown_contents = if hidden {
+        HIDDEN_MESSAGE.to_string()
+    } else {
+        contents.clone()
+    };
+    let message = Message {
+        recipient: recipient.clone(),
+        hidden,
+        session: session(&jar),
+        ..construct_reply(parent_id, shown_contents, sender.clone(), !is_command)
+    };
+    let mut message = store_message(state.store.as_ref(), message).await;
+    // Only the text is screened, so the files of hidden messages aren't kept
+    // like those of held messages
+    let dropped_uploads = hidden && !uploads.is_empty();
+    let uploads = if hidden { vec![] } else { uploads };
+    if let (Some((action, reason)), Some(id)) = (screened, message.id) {
+        queue_review(state.store.as_ref(), id, action, reason, &sender, &contents).await;
+        if hidden {
+            let mut notice = "Your message is hidden until an admin reviews it.".to_string();
+            if dropped_uploads {
+                notice.push_str(" Its attachments weren't kept.");
+            }
+            send_notice(&tx, &sender, notice);
+        }
+    }
+    if !hidden {
+        state.archive_index.add(&message);
+    }
+    if let Some(id) = message.id {
+        message.attachments =
+            attachments::save(state.files.as_ref(), state.store.as_ref(), id, uploads).await;
+        message.previews = match unfurl::cached(state.store.as_ref(), &message.source).await {
+            Ok(previews) => previews,
+            Err(e) => {
+                log::error!("Failed to load link previews:\n{e}");
+                vec![]
+            }
+        };
+        unfurl::spawn_unfurl(
+            state.store.clone(),
+            state.unfurl.clone(),
+            tx.clone(),
+            &message,
+        );
+    }
+    if let Some(parent_id) = parent_id {
+        send_reply_count(state.store.as_ref(), tx.clone(), parent_id).await;
+    }
     let tmsg = message.clone();
 
+    // Bots can answer messages on their own, except in direct messages, and
+    // are asked the whole message. Hidden messages aren't acted on at all, not
+    // even when they are commands.
+    let message_command = match message_command {
+        _ if hidden => None,
+        None if recipient.is_none() => triggered_bot(&state, &message, false).await.map(|bot| {
+            Ok(MessageCommand::QueryBot {
+                bot: Some(bot),
+                query: contents.clone(),
+            })
+        }),
+        message_command => message_command,
+    };
+
+    // Bot queries have their own limits and daily quotas. The message asking
+    // is still sent when they are refused, the bot just doesn't answer it.
+    let message_command = match message_command {
+        Some(Ok(MessageCommand::QueryBot { .. })) => {
+            match bot_query_refusal(&state, &sender, ip).await {
+                Some(refusal) => {
+                    send_notice(&tx, &sender, refusal);
+                    None
+                }
+                None => message_command,
+            }
+        }
+        _ => message_command,
+    };
+
     match message_command {
         Some(Ok(MessageCommand::NumUsersOnlineQuery)) => {
             let num_receivers = tx.receiver_count();
             send_message_delayed_backend(
                 tx.clone(),
-                construct_message(
+                construct_reply(
+                    parent_id,
                     format!("Users currently online: {num_receivers}"),
                     "Server",
                     false,
@@ -187,83 +712,208 @@ pub async fn send_message(
             );
         }
         Some(Ok(MessageCommand::QueryBot { bot, query })) => {
-            let tx = tx.clone();
-            tokio::task::spawn_blocking(move || {
-                let ai_context = state.0.ai_context;
-                let response = Handle::current().block_on(ai_context.lock().unwrap().get_response(
-                    &query,
-                    &sender,
-                    bot.as_deref(),
-                ));
-                if let Ok(response) = response {
-                    let message = construct_message(
-                        response.response,
-                        format!("{} (Bot)", response.bot_name),
-                        BOT_RESPONSES_NOTIFY,
-                    );
-                    send_message_backend(tx, message);
+            let asker = sender.clone();
+            ask_bot(state.0.clone(), tx.clone(), &message, asker, bot, query, 0).await;
+        }
+        Some(Ok(MessageCommand::Search { terms })) => {
+            // The results are posted publicly, so direct messages are left out
+            let filter = SearchFilter {
+                query: terms.clone(),
+                sender: None,
+                room: None,
+                from: None,
+                until: None,
+                viewer: None,
+            };
+            let response = match state
+                .store
+                .search_messages(&filter, MAX_COMMAND_SEARCH_RESULTS)
+                .await
+            {
+                Ok(results) => format_search_results(&terms, &results),
+                Err(e) => {
+                    log::error!("Failed to search messages:\n{e}");
+                    "Searching failed.".to_string()
                 }
-            });
+            };
+            send_message_delayed_backend(
+                tx.clone(),
+                construct_reply(parent_id, response, "Server", false),
+            );
+        }
+        Some(Ok(MessageCommand::Usage)) => {
+            let summary = match state
+                .store
+                .ai_usage(usage::usage_since(), Some(&user_key(&sender)))
+                .await
+            {
+                Ok(rows) => usage::summary(&rows, &state.ai_quotas),
+                Err(e) => {
+                    log::error!("Failed to load AI usage:\n{e}");
+                    "Loading your usage failed.".to_string()
+                }
+            };
+            send_notice(&tx, &sender, summary);
         }
         Some(Ok(MessageCommand::Help)) => {
             send_message_delayed_backend(
                 tx.clone(),
-                construct_message(HELP_MESSAGE, "Server", false),
+                construct_reply(parent_id, HELP_MESSAGE, "Server", false),
             );
         }
-        Some(Ok(MessageCommand::CreateBot { name, lang, config })) => {
+        Some(Ok(MessageCommand::CreateBot {
+            name,
+            settings,
+            config,
+        })) => {
+            // Bots can't take the names of users either, who would then be
+            // taken for the bot
+            let taken_by_user = match state.store.get_user(&user_key(&name)).await {
+                Ok(user) => user.is_some(),
+                Err(e) => {
+                    log::error!("Failed to load user {name}:\n{e}");
+                    true
+                }
+            };
+            let checked_settings = state.ai_context.lock().unwrap().check_settings(&settings);
+            let response = match check_name(&state, &name) {
+                Err(e) => format!("The bot can't be called that. {e}"),
+                Ok(()) if taken_by_user => {
+                    "The bot can't be called that. A user goes by that name.".to_string()
+                }
+                Ok(()) => match checked_settings {
+                    Err(e) => e,
+                    Ok(()) => {
+                        let mut bot = Bot::new(name, sender, Some(config), None);
+                        bot.apply(settings);
+                        state.0.ai_context.lock().unwrap().add_bot(bot.clone());
+                        save_bot(state.store.as_ref(), &bot).await;
+                        "New bot created.".to_string()
+                    }
+                },
+            };
             send_message_delayed_backend(
                 tx.clone(),
-                construct_message("New bot created.", "System", false),
+                construct_reply(parent_id, response, "System", false),
+            );
+        }
+        Some(Ok(MessageCommand::EditBot {
+            name,
+            settings,
+            config,
+        })) => {
+            // Only the creator of a bot and admins can change it
+            let edited = {
+                let mut ai_context = state.ai_context.lock().unwrap();
+                let checked_settings = ai_context.check_settings(&settings);
+                ai_context
+                    .edit_bot(&name, |bot| {
+                        if user_key(bot.creator()) != user_key(&sender) && role != Some(Role::Admin)
+                        {
+                            return Err("You can only edit a bot you created.".to_string());
+                        }
+                        checked_settings?;
+                        bot.apply(settings);
+                        if let Some(config) = config {
+                            bot.set_custom_config(config);
+                        }
+                        Ok(())
+                    })
+                    .unwrap_or_else(|| Err("There is no bot by that name.".to_string()))
+            };
+            let response = match edited {
+                Ok(bot) => {
+                    save_bot(state.store.as_ref(), &bot).await;
+                    "Bot updated.".to_string()
+                }
+                Err(e) => e,
+            };
+            send_message_delayed_backend(
+                tx.clone(),
+                construct_reply(parent_id, response, "System", false),
             );
-            state
-                .0
-                .ai_context
-                .lock()
-                .unwrap()
-                .add_bot(Bot::new(name, sender, Some(config), lang));
         }
         Some(Ok(MessageCommand::ListBots)) => {
-            let bots_list = state
-                .0
-                .ai_context
-                .lock()
-                .unwrap()
-                .bots()
-                .into_iter()
-                .map(|i| format!("- {} (created by {})", i.name(), i.creator()))
-                .collect::<Vec<_>>()
-                .join("\n");
+            let bots_list = {
+                let ai_context = state.ai_context.lock().unwrap();
+                ai_context
+                    .bots()
+                    .iter()
+                    .map(|bot| {
+                        format!(
+                            "- {} (created by {}, {} on {}, answers {})",
+                            bot.name(),
+                            bot.creator(),
+                            ai_context.model_of(bot),
+                            bot.provider(),
+                            bot.triggers().describe()
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
             send_message_delayed_backend(
                 tx.clone(),
-                construct_message(format!("Bots online:\n{bots_list}"), "System", false),
+                construct_reply(
+                    parent_id,
+                    format!("Bots online:\n{bots_list}"),
+                    "System",
+                    false,
+                ),
             );
         }
         Some(Ok(MessageCommand::RemoveBot { bot })) => {
-            if state
+            let removed = state
                 .0
                 .ai_context
                 .lock()
                 .unwrap()
-                .remove_bot_by_name(bot)
-                .is_some()
-            {
+                .remove_bot_by_name(bot.clone())
+                .is_some();
+            if removed {
+                if let Err(e) = state.store.delete_bot(&bot).await {
+                    log::error!("Failed to delete bot {bot}:\n{e}");
+                }
                 send_message_delayed_backend(
                     tx.clone(),
-                    construct_message("Bot removed.", "System", false),
+                    construct_reply(parent_id, "Bot removed.", "System", false),
                 );
             } else {
                 send_message_delayed_backend(
                     tx.clone(),
-                    construct_message("There is no bot by that name.", "System", false),
+                    construct_reply(parent_id, "There is no bot by that name.", "System", false),
                 );
             }
         }
+        Some(Ok(MessageCommand::Moderate(command))) => {
+            let response = if role.is_none() {
+                "Only moderators can do that.".to_string()
+            } else {
+                match moderation::apply(state.store.as_ref(), &tx, &sender, command).await {
+                    Ok(announcement) => announcement,
+                    Err(e) => {
+                        log::error!("Failed to apply moderation action:\n{e}");
+                        "That didn't work.".to_string()
+                    }
+                }
+            };
+            send_message_delayed_backend(
+                tx.clone(),
+                construct_reply(parent_id, response, "System", false),
+            );
+        }
+        Some(Err(MessageParseError::InvalidBotSettings(e))) => {
+            send_message_delayed_backend(
+                tx.clone(),
+                construct_reply(parent_id, e, "System", false),
+            );
+        }
         Some(Err(_)) => {
             let message = form.contents.clone();
             send_message_delayed_backend(
                 tx.clone(),
-                construct_message(
+                construct_reply(
+                    parent_id,
                     format!(
                         "Invalid command `{}`. Use !help to list valid commands",
                         message
@@ -273,7 +923,7 @@ pub async fn send_message(
                 ),
             );
         }
-        None => {}
+        Some(Ok(MessageCommand::DirectMessage { .. })) | None => {}
     }
 
     if form.contents.chars().next().is_some_and(|c| c == '!') {};
@@ -282,46 +932,1450 @@ pub async fn send_message(
     // noticed.
     // TODO: Work more on this
     send_message_backend(tx, tmsg);
-    templates::MessageTemplate { message, tz }.into_response()
+    templates::MessageTemplate {
+        message,
+        tz,
+        viewer: sender_name,
+        can_modify: true,
+        admin,
+    }
+    .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AdminSignInPayload {
+    token: String,
+}
+
+pub async fn admin() -> impl IntoResponse {
+    templates::AdminSignIn { failed: false }
+}
+
+pub async fn admin_sign_in(
+    state: State<AppState>,
+    jar: CookieJar,
+    Form(payload): Form<AdminSignInPayload>,
+) -> impl IntoResponse {
+    if role_of_token(&state, &payload.token).is_none() {
+        return (
+            StatusCode::FORBIDDEN,
+            templates::AdminSignIn { failed: true },
+        )
+            .into_response();
+    }
+    let jar = jar.add(Cookie::new("admin-token", payload.token));
+    (jar, Redirect::to("/feed")).into_response()
+}
+
+/// Get the role of the user making a request, which comes from the token
+/// they signed in with at `/admin`
+fn role(state: &AppState, jar: &CookieJar) -> Option<Role> {
+    role_of_token(state, jar.get("admin-token")?.value())
+}
+
+fn role_of_token(state: &AppState, token: &str) -> Option<Role> {
+    if state.admin_token.as_deref() == Some(token) {
+        Some(Role::Admin)
+    } else if state.moderator_token.as_deref() == Some(token) {
+        Some(Role::Moderator)
+    } else {
+        None
+    }
+}
+
+fn is_admin(state: &AppState, jar: &CookieJar) -> bool {
+    role(state, jar) == Some(Role::Admin)
+}
+
+/// Whether the user making a request is allowed to edit or delete a message,
+/// which senders can only do from the session they sent it from
+fn can_modify_message(state: &AppState, jar: &CookieJar, message: &Message) -> bool {
+    is_admin(state, jar) || message.is_sent_from(session(jar).as_deref())
+}
+
+pub async fn edit_message(
+    state: State<AppState>,
+    Extension(tx): Extension<RoomsStream>,
+    jar: CookieJar,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<i32>,
+    Form(form): Form<MessageNew>,
+) -> Result<StatusCode, ApiError> {
+    let message = state
+        .store
+        .get_message(id)
+        .await?
+        .ok_or(ApiError::DoesNotExist)?;
+    if !can_modify_message(&state, &jar, &message) {
+        return Err(ApiError::Forbidden);
+    }
+    // Edits count as sending a message, so the sender has to be allowed to
+    // send one. Anyone else editing it is an admin.
+    let admin = is_admin(&state, &jar);
+    if !admin {
+        let sender = &message.sender;
+        if let Some(sanction) = moderation::blocking_sanction(state.store.as_ref(), sender).await? {
+            send_notice(&tx, sender, moderation::describe_sanction(&sanction));
+            return Err(ApiError::Forbidden);
+        }
+        if let Err(wait) = state.rate_limits.messages.take(&user_key(sender), ip) {
+            send_notice(
+                &tx,
+                sender,
+                format!(
+                    "You are editing messages too quickly. Try again in {}.",
+                    ratelimit::format_wait(wait)
+                ),
+            );
+            return Ok(StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
+    // Edits are screened like new messages, except that posted messages can't
+    // be held, so edits that would be are hidden instead
+    let screened = match (&message.recipient, admin) {
+        (None, false) => screen_message(&state, message.parent_id, &form.contents).await,
+        _ => None,
+    };
+    let hidden = matches!(screened, Some((ScreenAction::Hide | ScreenAction::Hold, _)));
+    // The text of a hidden message was never shown, so the edit replaces it
+    // in the queue too
+    if message.hidden {
+        for review in state.store.reviews().await? {
+            if review.message == Some(id) && review.action == ScreenAction::Hide {
+                state.store.delete_review(review.id).await?;
+            }
+        }
+    }
+    let shown_contents = if hidden {
+        HIDDEN_MESSAGE
+    } else {
+        &form.contents
+    };
+    let previews = unfurl::cached(state.store.as_ref(), shown_contents).await?;
+    let message = Message {
+        contents: render::render_message(shown_contents),
+        source: shown_contents.to_string(),
+        hidden,
+        edited_at: Some(Utc::now()),
+        reactions: state.store.get_reactions(id).await?,
+        attachments: state.store.get_attachments(id).await?,
+        previews,
+        ..message
+    };
+    state.store.update_message(&message).await?;
+    if let Some((action, reason)) = screened {
+        let action = if hidden { ScreenAction::Hide } else { action };
+        queue_review(
+            state.store.as_ref(),
+            id,
+            action,
+            reason,
+            &message.sender,
+            &form.contents,
+        )
+        .await;
+        if hidden {
+            send_notice(
+                &tx,
+                &message.sender,
+                "Your edit is hidden until an admin reviews it.",
+            );
+        }
+    }
+    if hidden {
+        state.archive_index.remove(id);
+    } else {
+        state.archive_index.add(&message);
+    }
+    unfurl::spawn_unfurl(
+        state.store.clone(),
+        state.unfurl.clone(),
+        tx.clone(),
+        &message,
+    );
+    send_event_backend(tx, ChatEvent::Edit(message));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_message(
+    state: State<AppState>,
+    Extension(tx): Extension<RoomsStream>,
+    jar: CookieJar,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, ApiError> {
+    let message = state
+        .store
+        .get_message(id)
+        .await?
+        .ok_or(ApiError::DoesNotExist)?;
+    if !can_modify_message(&state, &jar, &message) {
+        return Err(ApiError::Forbidden);
+    }
+    remove_message(&state, tx, &message).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Delete a message with its replies and files, and take it off the clients
+async fn remove_message(
+    state: &AppState,
+    tx: Sender<ChatEvent>,
+    message: &Message,
+) -> sqlx::Result<()> {
+    let Some(id) = message.id else {
+        return Ok(());
+    };
+    let keys = state.store.delete_message(id).await?;
+    state.archive_index.remove(id);
+    attachments::delete_files(state.files.as_ref(), &keys).await;
+    send_event_backend(tx.clone(), ChatEvent::Delete(id));
+    if let Some(parent_id) = message.parent_id {
+        send_reply_count(state.store.as_ref(), tx, parent_id).await;
+    }
+    Ok(())
+}
+
+/// Pin a message if it isn't pinned, otherwise unpin it. Only admins can pin
+/// messages, since pinned messages are exempt from retention policies.
+pub async fn toggle_pin(
+    state: State<AppState>,
+    Extension(tx): Extension<RoomsStream>,
+    jar: CookieJar,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Err(ApiError::Forbidden);
+    }
+    let message = state
+        .store
+        .get_message(id)
+        .await?
+        .ok_or(ApiError::DoesNotExist)?;
+    state.store.set_pinned(id, !message.pinned).await?;
+    let message = Message {
+        pinned: !message.pinned,
+        reactions: state.store.get_reactions(id).await?,
+        attachments: state.store.get_attachments(id).await?,
+        previews: unfurl::cached(state.store.as_ref(), &message.source).await?,
+        ..message
+    };
+    send_event_backend(tx, ChatEvent::Edit(message));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn retention(
+    state: State<AppState>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Ok(Redirect::to("/admin").into_response());
+    }
+    let tz = jar
+        .get("timezone")
+        .and_then(|tz| tz.value().parse::<i32>().ok())
+        .unwrap_or_default();
+    let mut reports = retention::report(state.store.as_ref()).await?;
+    // Every room is listed so that policies can be added to it, along with
+    // the messages outside of rooms
+    let scopes = std::iter::once((None, "Main feed and direct messages".to_string()))
+        .chain(
+            state
+                .store
+                .rooms()
+                .await?
+                .into_iter()
+                .map(|room| (Some(room.id), room.name)),
+        )
+        .map(|(room, name)| {
+            let report = match reports.iter().position(|report| report.policy.room == room) {
+                Some(index) => reports.swap_remove(index),
+                None => retention::RetentionReport {
+                    policy: RetentionPolicy {
+                        room,
+                        ..Default::default()
+                    },
+                    messages: vec![],
+                },
+            };
+            (name, report)
+        })
+        .collect();
+    Ok(RetentionTemplate { scopes, tz }.into_response())
+}
+
+#[derive(Deserialize)]
+pub struct RetentionPayload {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    room: Option<i32>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    max_age_days: Option<i32>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    max_count: Option<i32>,
+}
+
+pub async fn set_retention(
+    state: State<AppState>,
+    jar: CookieJar,
+    Form(payload): Form<RetentionPayload>,
+) -> Result<Redirect, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Err(ApiError::Forbidden);
+    }
+    if payload.max_age_days.is_some_and(|days| days < 0)
+        || payload.max_count.is_some_and(|count| count < 0)
+    {
+        return Err(ApiError::BadRequest);
+    }
+    let policy = RetentionPolicy {
+        room: payload.room,
+        max_age_days: payload.max_age_days,
+        max_count: payload.max_count,
+    };
+    state.store.set_retention_policy(&policy).await?;
+    Ok(Redirect::to("/admin/retention"))
+}
+
+pub async fn review(state: State<AppState>, jar: CookieJar) -> Result<impl IntoResponse, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Ok(Redirect::to("/admin").into_response());
+    }
+    let tz = jar
+        .get("timezone")
+        .and_then(|tz| tz.value().parse::<i32>().ok())
+        .unwrap_or_default();
+    let policies = state.store.screen_policies().await?;
+    // Direct messages aren't screened, so unlike retention policies the main
+    // feed's policy is only for the main feed
+    let scopes = std::iter::once((None, "Main feed".to_string()))
+        .chain(
+            state
+                .store
+                .rooms()
+                .await?
+                .into_iter()
+                .map(|room| (Some(room.id), room.name)),
+        )
+        .map(|(room, name)| {
+            let policy = policies
+                .iter()
+                .find(|policy| policy.room == room)
+                .cloned()
+                .unwrap_or(ScreenPolicy {
+                    room,
+                    action: ScreenAction::default(),
+                });
+            (name, policy)
+        })
+        .collect();
+    Ok(ReviewTemplate {
+        screening: state.screening.is_on(),
+        reviews: state.store.reviews().await?,
+        scopes,
+        actions: ScreenAction::ALL,
+        tz,
+    }
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ScreenPolicyPayload {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    room: Option<i32>,
+    action: ScreenAction,
+}
+
+pub async fn set_screen_policy(
+    state: State<AppState>,
+    jar: CookieJar,
+    Form(payload): Form<ScreenPolicyPayload>,
+) -> Result<Redirect, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Err(ApiError::Forbidden);
+    }
+    let policy = ScreenPolicy {
+        room: payload.room,
+        action: payload.action,
+    };
+    state.store.set_screen_policy(&policy).await?;
+    Ok(Redirect::to("/admin/review"))
+}
+
+#[derive(Deserialize)]
+pub struct ReviewPayload {
+    id: i32,
+}
+
+/// Post a held message, show the text of a hidden message, or dismiss a
+/// flagged message
+pub async fn release_review(
+    state: State<AppState>,
+    jar: CookieJar,
+    Extension(tx): Extension<Sender<ChatEvent>>,
+    Form(payload): Form<ReviewPayload>,
+) -> Result<Redirect, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Err(ApiError::Forbidden);
+    }
+    let review = state
+        .store
+        .get_review(payload.id)
+        .await?
+        .ok_or(ApiError::DoesNotExist)?;
+    match (review.action, review.message) {
+        (ScreenAction::Hold, _) => {
+            let message = Message {
+                session: review.session.clone(),
+                ..construct_reply(review.parent_id, &review.source, &review.sender, true)
+            };
+            let message = store_message(state.store.as_ref(), message).await;
+            state.archive_index.add(&message);
+            if let Some(parent_id) = review.parent_id {
+                send_reply_count(state.store.as_ref(), tx.clone(), parent_id).await;
+            }
+            send_message_backend(tx.clone(), message);
+            send_notice(&tx, &review.sender, "Your held message was posted.");
+        }
+        (ScreenAction::Hide, Some(id)) => {
+            // Messages edited since they were hidden keep the edit
+            if let Some(message) = state
+                .store
+                .get_message(id)
+                .await?
+                .filter(|message| message.hidden)
+            {
+                let message = Message {
+                    contents: render::render_message(&review.source),
+                    source: review.source.clone(),
+                    hidden: false,
+                    reactions: state.store.get_reactions(id).await?,
+                    attachments: state.store.get_attachments(id).await?,
+                    previews: unfurl::cached(state.store.as_ref(), &review.source).await?,
+                    ..message
+                };
+                state.store.update_message(&message).await?;
+                state.archive_index.add(&message);
+                unfurl::spawn_unfurl(
+                    state.store.clone(),
+                    state.unfurl.clone(),
+                    tx.clone(),
+                    &message,
+                );
+                send_event_backend(tx, ChatEvent::Edit(message));
+            }
+        }
+        _ => {}
+    }
+    state.store.delete_review(review.id).await?;
+    Ok(Redirect::to("/admin/review"))
+}
+
+/// Delete a reviewed message, which for held messages only drops them from
+/// the queue
+pub async fn delete_review(
+    state: State<AppState>,
+    jar: CookieJar,
+    Extension(tx): Extension<Sender<ChatEvent>>,
+    Form(payload): Form<ReviewPayload>,
+) -> Result<Redirect, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Err(ApiError::Forbidden);
+    }
+    let review = state
+        .store
+        .get_review(payload.id)
+        .await?
+        .ok_or(ApiError::DoesNotExist)?;
+    if let Some(id) = review.message {
+        if let Some(message) = state.store.get_message(id).await? {
+            remove_message(&state, tx, &message).await?;
+        }
+    }
+    state.store.delete_review(review.id).await?;
+    Ok(Redirect::to("/admin/review"))
+}
+
+pub async fn usage_page(
+    state: State<AppState>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Ok(Redirect::to("/admin").into_response());
+    }
+    let rows = state.store.ai_usage(usage::usage_since(), None).await?;
+    Ok(UsageTemplate {
+        days: usage::totals(&rows, |usage| usage.day.format("%d %b, %Y").to_string()),
+        bots: usage::totals(&rows, |usage| usage.bot.clone()),
+        users: usage::totals(&rows, |usage| usage.user_key.clone()),
+        quotas: state.ai_quotas.clone(),
+    }
+    .into_response())
+}
+
+/// How many entries of the audit log are shown at `/admin/moderation`
+const MODERATION_LOG_LIMIT: i64 = 200;
+
+pub async fn moderation(
+    state: State<AppState>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, ApiError> {
+    if role(&state, &jar).is_none() {
+        return Ok(Redirect::to("/admin").into_response());
+    }
+    let tz = jar
+        .get("timezone")
+        .and_then(|tz| tz.value().parse::<i32>().ok())
+        .unwrap_or_default();
+    Ok(ModerationTemplate {
+        sanctions: state.store.active_sanctions(None).await?,
+        log: state.store.moderation_log(MODERATION_LOG_LIMIT).await?,
+        tz,
+    }
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct LiftSanctionPayload {
+    user: String,
+    kind: SanctionKind,
 }
+
+/// Unmute or unban a user from the moderation page
+pub async fn lift_sanction(
+    state: State<AppState>,
+    jar: CookieJar,
+    Extension(tx): Extension<Sender<ChatEvent>>,
+    Form(payload): Form<LiftSanctionPayload>,
+) -> Result<Redirect, ApiError> {
+    if role(&state, &jar).is_none() {
+        return Err(ApiError::Forbidden);
+    }
+    let moderator = jar
+        .get("sender-name")
+        .map(|name| name.value().to_string())
+        .unwrap_or_else(|| "A moderator".to_string());
+    let action = match payload.kind {
+        SanctionKind::Mute => ModerationAction::Unmute,
+        SanctionKind::Ban => ModerationAction::Unban,
+    };
+    let command = Moderation {
+        action,
+        user: payload.user,
+        duration: None,
+        reason: None,
+    };
+    moderation::apply(state.store.as_ref(), &tx, &moderator, command).await?;
+    Ok(Redirect::to("/admin/moderation"))
+}
+
+pub async fn archives(
+    state: State<AppState>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Ok(Redirect::to("/admin").into_response());
+    }
+    Ok(ArchiveAdminTemplate {
+        rooms: state.store.rooms().await?,
+        result: None,
+    }
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    room: Option<i32>,
+    #[serde(default)]
+    format: archive::Format,
+}
+
+pub async fn export_archive(
+    state: State<AppState>,
+    jar: CookieJar,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Err(ApiError::Forbidden);
+    }
+    let archive = archive::export(state.store.as_ref(), params.room, params.format).await?;
+    let scope = match params.room {
+        Some(room) => format!("room-{room}"),
+        None => "main".to_string(),
+    };
+    let filename = format!(
+        "myrss-{scope}-{}.{}",
+        Utc::now().format("%Y-%m-%d"),
+        params.format.extension()
+    );
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                params.format.content_type().to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        archive,
+    ))
+}
+
+pub async fn import_archive(
+    state: State<AppState>,
+    jar: CookieJar,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    if !is_admin(&state, &jar) {
+        return Err(ApiError::Forbidden);
+    }
+    let mut room = None;
+    let mut archive = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| ApiError::BadRequest)?
+    {
+        match field.name() {
+            Some("room") => {
+                let value = field.text().await.map_err(|_| ApiError::BadRequest)?;
+                if !value.is_empty() {
+                    room = Some(value.parse().map_err(|_| ApiError::BadRequest)?);
+                }
+            }
+            Some("archive") => {
+                archive = Some(field.text().await.map_err(|_| ApiError::BadRequest)?);
+            }
+            _ => {}
+        }
+    }
+    let archive = archive.ok_or(ApiError::BadRequest)?;
+    let result = match archive::import(state.store.as_ref(), &archive, room).await {
+        Ok(summary) => {
+            let mut ai_context = state.ai_context.lock().unwrap();
+            for bot in &summary.bots {
+                ai_context.add_bot(bot.clone());
+            }
+            let mut result = format!(
+                "Imported {} messages and {} bots.",
+                summary.messages,
+                summary.bots.len()
+            );
+            if !summary.skipped_bots.is_empty() {
+                result.push_str(&format!(
+                    " Skipped bots that already exist: {}.",
+                    summary.skipped_bots.join(", ")
+                ));
+            }
+            Ok(result)
+        }
+        Err(e) => Err(format!("{e:#}")),
+    };
+    if result.is_ok() {
+        if let Err(e) = state.archive_index.rebuild(state.store.as_ref()).await {
+            log::error!("Failed to index imported messages:\n{e}");
+        }
+    }
+    Ok(ArchiveAdminTemplate {
+        rooms: state.store.rooms().await?,
+        result: Some(result),
+    })
+}
+
+pub async fn thread(
+    state: State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<i32>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(viewer) = jar.get("sender-name") else {
+        return Ok(Redirect::to("/").into_response());
+    };
+    let tz = jar
+        .get("timezone")
+        .and_then(|tz| tz.value().parse::<i32>().ok())
+        .unwrap_or_default();
+    let mut root = state
+        .store
+        .get_message(id)
+        .await?
+        .filter(|message| message.recipient.is_none())
+        .ok_or(ApiError::DoesNotExist)?;
+    if let Some(parent_id) = root.parent_id {
+        return Ok(Redirect::to(&format!("/messages/{parent_id}/thread")).into_response());
+    }
+    load_details(state.store.as_ref(), std::slice::from_mut(&mut root)).await?;
+    let mut replies = state.store.get_replies(id).await?;
+    load_details(state.store.as_ref(), &mut replies).await?;
+    replies.reverse();
+    Ok(ThreadTemplate {
+        root,
+        replies,
+        tz,
+        viewer: viewer.value().to_string(),
+        session: session(&jar),
+        admin: is_admin(&state, &jar),
+    }
+    .into_response())
+}
+
+pub async fn direct_messages(
+    state: State<AppState>,
+    jar: CookieJar,
+    Path(user): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(viewer) = jar.get("sender-name") else {
+        return Ok(Redirect::to("/").into_response());
+    };
+    let tz = jar
+        .get("timezone")
+        .and_then(|tz| tz.value().parse::<i32>().ok())
+        .unwrap_or_default();
+    let viewer = viewer.value().to_string();
+    if !owns_name(&state, &jar, &viewer).await? {
+        return Err(ApiError::Forbidden);
+    }
+    let mut messages = state
+        .store
+        .get_direct_messages(&user_key(&viewer), &user_key(&user))
+        .await?;
+    load_details(state.store.as_ref(), &mut messages).await?;
+    // Show the name the other user last connected with rather than however
+    // it was typed in the URL
+    let (user, last_seen) = match state.store.get_user(&user_key(&user)).await? {
+        Some(known) => (known.name, Some(known.last_seen)),
+        None => (user, None),
+    };
+    Ok(DirectMessagesTemplate {
+        user_key: user_key(&user),
+        user,
+        last_seen,
+        messages,
+        tz,
+        viewer,
+        session: session(&jar),
+        admin: is_admin(&state, &jar),
+    }
+    .into_response())
+}
+
+pub async fn attachment(
+    state: State<AppState>,
+    jar: CookieJar,
+    Path(key): Path<String>,
+) -> Result<Response, ApiError> {
+    serve_attachment(&state, &jar, &key, false).await
+}
+
+pub async fn attachment_thumbnail(
+    state: State<AppState>,
+    jar: CookieJar,
+    Path(key): Path<String>,
+) -> Result<Response, ApiError> {
+    serve_attachment(&state, &jar, &key, true).await
+}
+
+/// Send the file of an attachment, or its thumbnail, to anyone who can see
+/// the message it is attached to
+async fn serve_attachment(
+    state: &AppState,
+    jar: &CookieJar,
+    key: &str,
+    thumbnail: bool,
+) -> Result<Response, ApiError> {
+    let viewer = jar.get("sender-name").ok_or(ApiError::Forbidden)?;
+    if !owns_name(state, jar, viewer.value()).await? {
+        return Err(ApiError::Forbidden);
+    }
+    let attachment = state
+        .store
+        .get_attachment(key)
+        .await?
+        .filter(|attachment| !thumbnail || attachment.has_thumbnail)
+        .ok_or(ApiError::DoesNotExist)?;
+    state
+        .store
+        .get_message(attachment.message)
+        .await?
+        .filter(|message| message.is_visible_to(viewer.value()))
+        // The files of hidden messages are only for their sender and admins
+        // until the message is released
+        .filter(|message| !message.hidden || can_modify_message(state, jar, message))
+        .ok_or(ApiError::DoesNotExist)?;
+    let (file_key, content_type, disposition) = if thumbnail {
+        (
+            attachments::thumbnail_key(key),
+            "image/png".to_string(),
+            "inline".to_string(),
+        )
+    } else {
+        (
+            attachment.key.clone(),
+            attachment.content_type.clone(),
+            attachments::content_disposition(&attachment),
+        )
+    };
+    let data = state
+        .files
+        .get(&file_key)
+        .await
+        .map_err(anyhow::Error::from)?
+        .ok_or(ApiError::DoesNotExist)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+            // Keep browsers from running anything that was uploaded
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (header::CONTENT_SECURITY_POLICY, "sandbox".to_string()),
+            // Files never change once they are uploaded
+            (
+                header::CACHE_CONTROL,
+                "private, max-age=31536000, immutable".to_string(),
+            ),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+/// Load the reactions, attachments and link previews of messages, which the
+/// store doesn't load along with them
+async fn load_details(store: &dyn ChatStore, messages: &mut [Message]) -> sqlx::Result<()> {
+    for message in messages {
+        let Some(id) = message.id else {
+            continue;
+        };
+        message.reactions = store.get_reactions(id).await?;
+        message.attachments = store.get_attachments(id).await?;
+        message.previews = unfurl::cached(store, &message.source).await?;
+    }
+    Ok(())
+}
+
+/// Deserialize empty form fields as `None`
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match Option::<String>::deserialize(deserializer)?
+        .as_deref()
+        .map(str::trim)
+    {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    q: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    sender: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    room: Option<i32>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    from: Option<NaiveDate>,
+    /// The last day to include
+    #[serde(default, deserialize_with = "empty_as_none")]
+    to: Option<NaiveDate>,
+}
+
+pub async fn search(
+    state: State<AppState>,
+    jar: CookieJar,
+    Query(params): Query<SearchParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(viewer) = jar.get("sender-name") else {
+        return Ok(Redirect::to("/").into_response());
+    };
+    let tz = jar
+        .get("timezone")
+        .and_then(|tz| tz.value().parse::<i32>().ok())
+        .unwrap_or_default();
+    let viewer = viewer.value().to_string();
+    if !owns_name(&state, &jar, &viewer).await? {
+        return Err(ApiError::Forbidden);
+    }
+    let results = match &params.q {
+        Some(query) => {
+            let filter = SearchFilter {
+                query: query.clone(),
+                sender: params.sender.as_deref().map(user_key),
+                room: params.room,
+                from: params.from.map(|date| local_midnight(date, tz)),
+                until: params
+                    .to
+                    .and_then(|date| date.succ_opt())
+                    .map(|date| local_midnight(date, tz)),
+                viewer: Some(user_key(&viewer)),
+            };
+            let terms = search_terms(query);
+            let mut results = state
+                .store
+                .search_messages(&filter, MAX_SEARCH_RESULTS)
+                .await?;
+            load_details(state.store.as_ref(), &mut results).await?;
+            for message in results.iter_mut() {
+                message.contents = render::highlight_terms(&message.contents, &terms);
+            }
+            Some(results)
+        }
+        None => None,
+    };
+    Ok(SearchTemplate {
+        rooms: state.store.rooms().await?,
+        query: params.q.unwrap_or_default(),
+        sender: params.sender.unwrap_or_default(),
+        room: params.room,
+        from: params.from.map(|date| date.to_string()).unwrap_or_default(),
+        to: params.to.map(|date| date.to_string()).unwrap_or_default(),
+        results,
+        tz,
+        viewer,
+    }
+    .into_response())
+}
+
+/// Get the start of a day in the user's timezone, which is given in minutes
+/// behind UTC like the `timezone` cookie
+fn local_midnight(date: NaiveDate, tz: i32) -> DateTime<Utc> {
+    date.and_time(NaiveTime::MIN).and_utc() + chrono::Duration::minutes(tz.into())
+}
+
+/// Get the words of a search to highlight in the results
+fn search_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty() && !term.eq_ignore_ascii_case("or"))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// List search results as a chat message
+fn format_search_results(terms: &str, results: &[Message]) -> String {
+    const SNIPPET_CHARS: usize = 80;
+    if results.is_empty() {
+        return format!("No messages found for \"{}\".", escape_markdown(terms));
+    }
+    let results = results
+        .iter()
+        .map(|message| {
+            let text = message
+                .source
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut snippet = text.chars().take(SNIPPET_CHARS).collect::<String>();
+            if text.chars().count() > SNIPPET_CHARS {
+                snippet.push_str("...");
+            }
+            format!(
+                "- **{}** ({}): {} [view](/messages/{}/thread)",
+                escape_markdown(&message.sender),
+                message.sent_date.format("%d %b, %Y"),
+                escape_markdown(&snippet),
+                message.id.unwrap_or_default(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let query = serde_urlencoded::to_string([("q", terms)]).unwrap_or_default();
+    format!(
+        "Search results for \"{}\":\n{results}\n\n[All results](/search?{query})",
+        escape_markdown(terms)
+    )
+}
+
+/// Escape all ASCII punctuation so that text shows up as written in Markdown
+fn escape_markdown(text: &str) -> String {
+    text.chars()
+        .flat_map(|c| {
+            if c.is_ascii_punctuation() {
+                vec!['\\', c]
+            } else {
+                vec![c]
+            }
+        })
+        .collect()
+}
+
+/// Get the messages in a thread up to a query to a bot, to show the bot as
+/// context
+async fn thread_context(
+    store: &dyn ChatStore,
+    parent_id: i32,
+    query_id: Option<i32>,
+) -> Vec<ContextMessage> {
+    let thread = match (
+        store.get_message(parent_id).await,
+        store.get_replies(parent_id).await,
+    ) {
+        (Ok(Some(root)), Ok(replies)) => std::iter::once(root).chain(replies),
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("Failed to load thread for bot context:\n{e}");
+            return vec![];
+        }
+        (Ok(None), _) => return vec![],
+    };
+    thread
+        .take_while(|message| query_id.is_none() || message.id != query_id)
+        .map(|message| ContextMessage {
+            sender: message.sender,
+            contents: message.source,
+        })
+        .collect()
+}
+
+/// Get the latest messages of a room for a bot that is shown them, leaving
+/// out the question itself
+async fn recent_context(
+    store: &dyn ChatStore,
+    room: Option<i32>,
+    query_id: Option<i32>,
+    count: usize,
+) -> Vec<ContextMessage> {
+    // One more than needed, in case the question is among them
+    let limit = count.saturating_add(1).try_into().unwrap_or(i64::MAX);
+    let messages = match store.latest_messages(room, limit).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            log::error!("Failed to load room for bot context:\n{e}");
+            return vec![];
+        }
+    };
+    let messages = messages
+        .into_iter()
+        .filter(|message| query_id.is_none() || message.id != query_id)
+        .collect::<Vec<_>>();
+    messages[messages.len().saturating_sub(count)..]
+        .iter()
+        .map(|message| ContextMessage {
+            sender: message.sender.clone(),
+            contents: message.source.clone(),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct ReactionPayload {
+    emoji: String,
+}
+
+pub async fn toggle_reaction(
+    state: State<AppState>,
+    Extension(tx): Extension<RoomsStream>,
+    jar: CookieJar,
+    Path(id): Path<i32>,
+    Form(payload): Form<ReactionPayload>,
+) -> Result<StatusCode, ApiError> {
+    let Some(user) = jar.get("sender-name") else {
+        return Err(ApiError::Forbidden);
+    };
+    if !is_valid_reaction(&payload.emoji) || check_name(&state, user.value()).is_err() {
+        return Err(ApiError::BadRequest);
+    }
+    if !owns_name(&state, &jar, user.value()).await? {
+        return Err(ApiError::Forbidden);
+    }
+    if let Some(sanction) =
+        moderation::blocking_sanction(state.store.as_ref(), user.value()).await?
+    {
+        send_notice(&tx, user.value(), moderation::describe_sanction(&sanction));
+        return Err(ApiError::Forbidden);
+    }
+    let message = state
+        .store
+        .get_message(id)
+        .await?
+        .filter(|message| message.is_visible_to(user.value()))
+        .ok_or(ApiError::DoesNotExist)?;
+    let reactions = state
+        .store
+        .toggle_reaction(id, user.value(), &payload.emoji)
+        .await?;
+    send_event_backend(
+        tx,
+        ChatEvent::Reactions {
+            message: id,
+            reactions,
+            participants: message
+                .recipient
+                .map(|recipient| [user_key(&message.sender), recipient]),
+        },
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reactions have to be a single short emoji (sequence). Only the ASCII
+/// characters that start keycap emoji are allowed so that reactions can't be
+/// used to post text.
+fn is_valid_reaction(emoji: &str) -> bool {
+    const MAX_REACTION_CHARS: usize = 8;
+    let count = emoji.chars().count();
+    count > 0
+        && count <= MAX_REACTION_CHARS
+        && !emoji.is_ascii()
+        && emoji
+            .chars()
+            .all(|c| !c.is_whitespace() && (!c.is_ascii() || matches!(c, '0'..='9' | '#' | '*')))
+}
+
+/// Construct a message in the thread of the message it responds to
+fn construct_reply(
+    parent_id: Option<i32>,
+    contents: impl ToString,
+    sender: impl ToString,
+    notify: bool,
+) -> Message {
+    Message {
+        parent_id,
+        ..construct_message(contents, sender, notify)
+    }
+}
+
 fn construct_message(contents: impl ToString, sender: impl ToString, notify: bool) -> Message {
-    let contents = contents.to_string();
-    let contents = ammonia::clean(&contents);
-    let contents = contents
-        .lines()
-        .map(|line| line.trim_end()) // Trim trailing spaces
-        .collect::<Vec<_>>() // Collect into a Vec
-        .join("  \n"); // Join with Markdown's line break syntax (two spaces + newline)
-
-    let contents = markdown::to_html(&contents); // Convert to HTML from Markdown
+    let source = contents.to_string();
+    let contents = render::render_message(&source);
     let sender = sender.to_string();
     Message {
+        id: None,
+        parent_id: None,
         sender,
+        recipient: None,
         contents,
+        source,
         sent_date: Utc::now(),
+        edited_at: None,
+        pinned: false,
+        hidden: false,
+        session: None,
         should_notify: notify,
+        reply_count: 0,
+        reactions: vec![],
+        attachments: vec![],
+        previews: vec![],
+    }
+}
+
+/// Save a message to the database, returning it with its new id. If saving
+/// fails the message is returned without an id so it can still be broadcast.
+async fn store_message(store: &dyn ChatStore, message: Message) -> Message {
+    match store.insert_message(&message).await {
+        Ok(id) => Message {
+            id: Some(id),
+            ..message
+        },
+        Err(e) => {
+            log::error!("Failed to save message:\n{e}");
+            message
+        }
+    }
+}
+
+/// Check the quotas and rate limits before a bot is asked something, getting
+/// why it can't be if it can't
+async fn bot_query_refusal(state: &AppState, sender: &str, ip: Option<IpAddr>) -> Option<String> {
+    match usage::check_quota(state.store.as_ref(), &state.ai_quotas, sender).await {
+        Ok(Some(refusal)) => Some(refusal),
+        Ok(None) => match state.rate_limits.bot_queries.take(&user_key(sender), ip) {
+            Ok(()) => None,
+            Err(wait) => Some(format!(
+                "You are asking bots too many questions. Try again in {}.",
+                ratelimit::format_wait(wait)
+            )),
+        },
+        Err(e) => {
+            log::error!("Failed to check the AI quotas:\n{e}");
+            Some("Bots can't be asked anything right now.".to_string())
+        }
+    }
+}
+
+/// Screen a public message in a thread, or in the main feed if `thread` is
+/// `None`, getting what the room's policy does with it and why if the
+/// classifier objects to it
+async fn screen_message(
+    state: &AppState,
+    thread: Option<i32>,
+    source: &str,
+) -> Option<(ScreenAction, String)> {
+    state
+        .screening
+        .screen(state.store.as_ref(), thread, source)
+        .await
+}
+
+/// Put a posted message in the review queue, with the text it was sent with
+async fn queue_review(
+    store: &dyn ChatStore,
+    message: i32,
+    action: ScreenAction,
+    reason: String,
+    sender: &str,
+    source: &str,
+) {
+    let review = Review {
+        id: 0,
+        message: Some(message),
+        action,
+        reason,
+        sender: sender.to_string(),
+        parent_id: None,
+        source: source.to_string(),
+        created_at: Utc::now(),
+        session: None,
+    };
+    if let Err(e) = store.add_review(&review).await {
+        log::error!("Failed to queue message {message} for review:\n{e}");
+    }
+}
+
+/// Get the bot that answers a message on its own, if any
+async fn triggered_bot(state: &AppState, message: &Message, from_bot: bool) -> Option<String> {
+    let thread = message.parent_id.or(message.id);
+    let room = match thread {
+        Some(thread) => match state.store.get_message_room(thread).await {
+            Ok(room) => room,
+            Err(e) => {
+                log::error!("Failed to load the room of message {thread}:\n{e}");
+                None
+            }
+        },
+        None => None,
+    };
+    let bots = state.bots.bots();
+    state.bot_cooldowns.triggered(
+        &bots,
+        &Incoming {
+            sender: &message.sender,
+            contents: &message.contents,
+            source: &message.source,
+            thread: message.parent_id,
+            room: room.map(|room| room.id),
+            from_bot,
+        },
+    )
+}
+
+/// Ask a bot about a message in the background and post its answer, which
+/// other bots can answer in turn. `asker` is the user who started the chain,
+/// who every answer in it counts against, and `depth` is how many bot answers
+/// in a row led to the message.
+async fn ask_bot(
+    state: AppState,
+    tx: Sender<ChatEvent>,
+    message: &Message,
+    asker: String,
+    bot: Option<String>,
+    query: String,
+    depth: u32,
+) {
+    let sender = message.sender.clone();
+    let parent_id = message.parent_id;
+    let context = match parent_id {
+        Some(parent_id) => thread_context(state.store.as_ref(), parent_id, message.id).await,
+        None => vec![],
+    };
+    let room = match parent_id {
+        Some(parent_id) => match state.store.get_message_room(parent_id).await {
+            Ok(room) => room,
+            Err(e) => {
+                log::error!("Failed to load the room of message {parent_id}:\n{e}");
+                None
+            }
+        },
+        None => None,
+    };
+    let room_context = state.bots.room_context_of(bot.as_deref());
+    let recent = if room_context > 0 {
+        recent_context(
+            state.store.as_ref(),
+            room.as_ref().map(|room| room.id),
+            message.id,
+            room_context,
+        )
+        .await
+    } else {
+        vec![]
+    };
+    let room = room.map(|room| room.name);
+    let sources = state
+        .archive_index
+        .retrieve(state.store.as_ref(), &query, message.id)
+        .await;
+    let tools = ChatTools {
+        store: state.store.clone(),
+        tx: tx.clone(),
+        presence: state.presence.clone(),
+        screening: state.screening.clone(),
+        reminders: state.reminders.clone(),
+        user: asker.clone(),
+        thread: parent_id,
+    };
+    // The context is only locked to start and finish the response, so other
+    // requests don't wait on the provider
+    let pending = state.ai_context.lock().unwrap().start_response(
+        &query,
+        &sender,
+        bot.as_deref(),
+        &Conversation {
+            room: room.as_deref(),
+            thread: &context,
+            recent: &recent,
+            sources: &sources,
+        },
+    );
+    let pending = match pending {
+        Ok(pending) => pending,
+        Err(e) => {
+            log::error!("Failed to ask a bot:\n{e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let response = match pending.run(Some(&tools)).await {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Failed to get a bot response:\n{e}");
+                return;
+            }
+        };
+        let bot = state.ai_context.lock().unwrap().finish_response(&response);
+        if let Some(bot) = bot {
+            save_bot(state.store.as_ref(), &bot).await;
+        }
+        if let Some(tokens) = &response.usage {
+            let recorded =
+                usage::record(state.store.as_ref(), &response.bot_name, &asker, tokens).await;
+            if let Err(e) = recorded {
+                log::error!("Failed to record AI usage:\n{e}");
+            }
+        }
+        let message = construct_reply(
+            parent_id,
+            response.response,
+            format!("{} (Bot)", response.bot_name),
+            BOT_RESPONSES_NOTIFY,
+        );
+        let message = store_message(state.store.as_ref(), message).await;
+        state.archive_index.add(&message);
+        if let Some(parent_id) = parent_id {
+            send_reply_count(state.store.as_ref(), tx.clone(), parent_id).await;
+        }
+        send_message_backend(tx.clone(), message.clone());
+        if depth + 1 < MAX_BOT_CHAIN {
+            answer_bot(state, tx, message, asker, depth + 1).await;
+        }
+    });
+}
+
+/// Let other bots answer a bot's answer on their own, charging `asker` like
+/// the first answer was. The future is boxed since it asks bots, which can
+/// call this again.
+fn answer_bot(
+    state: AppState,
+    tx: Sender<ChatEvent>,
+    message: Message,
+    asker: String,
+    depth: u32,
+) -> BoxFuture<'static, ()> {
+    async move {
+        let Some(bot) = triggered_bot(&state, &message, true).await else {
+            return;
+        };
+        if let Some(refusal) = bot_query_refusal(&state, &asker, None).await {
+            log::info!(
+                "Not letting {bot} answer {} for {asker}: {refusal}",
+                message.sender
+            );
+            return;
+        }
+        let query = message.source.clone();
+        ask_bot(state, tx, &message, asker, Some(bot), query, depth).await;
+    }
+    .boxed()
+}
+
+async fn save_bot(store: &dyn ChatStore, bot: &Bot) {
+    if let Err(e) = store.save_bot(bot).await {
+        log::error!("Failed to save bot {}:\n{e}", bot.name());
     }
 }
-fn send_message_backend(tx: Sender<Message>, message: Message) {
-    if tx.send(message).is_err() {
+
+async fn send_reply_count(store: &dyn ChatStore, tx: Sender<ChatEvent>, parent_id: i32) {
+    match store.count_replies(parent_id).await {
+        Ok(count) => send_event_backend(
+            tx,
+            ChatEvent::Replies {
+                message: parent_id,
+                count,
+            },
+        ),
+        Err(e) => log::error!("Failed to count replies:\n{e}"),
+    }
+}
+
+/// Send a System message that only one user sees
+fn send_notice(tx: &Sender<ChatEvent>, user: &str, text: impl ToString) {
+    send_event_backend(
+        tx.clone(),
+        ChatEvent::Notice {
+            user: user_key(user),
+            message: construct_message(text, "System", false),
+        },
+    );
+}
+
+fn send_message_backend(tx: Sender<ChatEvent>, message: Message) {
+    send_event_backend(tx, ChatEvent::Message(message));
+}
+
+fn send_event_backend(tx: Sender<ChatEvent>, event: ChatEvent) {
+    if tx.send(event).is_err() {
         log::warn!("Nobody is listening to the stream");
     }
 }
 
-fn send_message_delayed_backend(tx: Sender<Message>, message: Message) {
+fn send_message_delayed_backend(tx: Sender<ChatEvent>, message: Message) {
     tokio::spawn(async move {
         tokio::time::sleep(Duration::from_millis(250)).await;
-        if tx.send(message).is_err() {
-            log::warn!("Nobody is listening to the stream");
-        }
+        send_message_backend(tx, message);
     });
 }
 
-pub async fn feed(jar: CookieJar) -> impl IntoResponse {
-    if jar.get("sender-name").is_none() {
-        return Redirect::to("/").into_response();
+pub async fn highlight_css() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/css")], highlight::stylesheet())
+}
+
+pub async fn feed(state: State<AppState>, jar: CookieJar) -> Result<Response, ApiError> {
+    let Some(name) = jar.get("sender-name") else {
+        return Ok(Redirect::to("/").into_response());
+    };
+    if let Err(e) = check_name(&state, name.value()) {
+        return Ok(banned_name(name.value(), e).into_response());
+    }
+    if let Some(sanction) = moderation::blocking_sanction(state.store.as_ref(), name.value())
+        .await?
+        .filter(|sanction| sanction.kind == SanctionKind::Ban)
+    {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            templates::Banned {
+                message: moderation::describe_sanction(&sanction),
+            },
+        )
+            .into_response());
     }
-    templates::FeedTemplate.into_response()
+    // Names chosen before sessions existed get one here
+    let name = name.value().to_string();
+    let jar = with_session(jar);
+    if !owns_name(&state, &jar, &name).await? {
+        return Ok(banned_name(&name, NameError::Taken).into_response());
+    }
+    Ok((jar, templates::FeedTemplate).into_response())
 }
 
 enum MessageCommand {
@@ -331,14 +2385,29 @@ enum MessageCommand {
     },
     CreateBot {
         name: String,
-        lang: Option<String>,
+        settings: BotSettings,
         config: String,
     },
+    EditBot {
+        name: String,
+        settings: BotSettings,
+        /// New instructions, if they are changed
+        config: Option<String>,
+    },
     RemoveBot {
         bot: String,
     },
     ListBots,
+    Search {
+        terms: String,
+    },
+    DirectMessage {
+        recipient: String,
+        contents: String,
+    },
+    Moderate(Moderation),
     NumUsersOnlineQuery,
+    Usage,
     Help,
 }
 
@@ -346,6 +2415,8 @@ enum MessageCommand {
 enum MessageParseError {
     #[error("Invalid command or command syntax entered")]
     InvalidCommand,
+    #[error("{0}")]
+    InvalidBotSettings(String),
 }
 
 fn parse_message_command(s: &str) -> Option<Result<MessageCommand, MessageParseError>> {
@@ -374,35 +2445,66 @@ fn parse_message_command(s: &str) -> Option<Result<MessageCommand, MessageParseE
             bot: Some(bot_name.to_string()),
             query,
         }))
+    } else if command == "msg" && command_input.split_whitespace().count() > 2 {
+        let recipient = command_input.split_whitespace().nth(1).unwrap();
+        let contents = command_input
+            .trim_start()
+            .strip_prefix("msg")
+            .unwrap_or_default()
+            .trim_start()
+            .strip_prefix(recipient)
+            .unwrap_or_default()
+            .trim_start()
+            .to_string();
+        Some(Ok(MessageCommand::DirectMessage {
+            recipient: recipient.to_string(),
+            contents,
+        }))
+    } else if command == "search" && command_input.split_whitespace().count() > 1 {
+        Some(Ok(MessageCommand::Search {
+            terms: command_input
+                .trim_start()
+                .strip_prefix("search")
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+        }))
     } else if command == "online" {
         Some(Ok(MessageCommand::NumUsersOnlineQuery))
+    } else if command == "usage" {
+        Some(Ok(MessageCommand::Usage))
     } else if command == "help" {
         Some(Ok(MessageCommand::Help))
     } else if command == "newbot" && command_input.split_whitespace().count() > 2 {
         let name = command_input.split_whitespace().nth(1).unwrap();
-        let lang_word = command_input.split_whitespace().nth(2).unwrap();
-        let lang = lang_word
-            .strip_prefix("lang=")
-            .map(|stripped| stripped.to_string());
-        let customizations = command_input
-            .chars()
-            .skip(6)
-            .skip_while(|c| c.is_whitespace())
-            .skip(name.len())
-            .skip_while(|c| c.is_whitespace());
-        let customizations_final: String = if let Some(ref lang) = lang {
-            customizations
-                .skip(lang.len() + 5)
-                .skip_while(|c| c.is_whitespace())
-                .collect()
-        } else {
-            customizations.collect()
+        let rest = command_input.trim_start()[command.len()..].trim_start();
+        let (settings, config) = match BotSettings::parse(&rest[name.len()..]) {
+            Ok(parsed) => parsed,
+            Err(e) => return Some(Err(MessageParseError::InvalidBotSettings(e))),
         };
         Some(Ok(MessageCommand::CreateBot {
             name: name.to_string(),
-            lang,
-            config: customizations_final,
+            settings,
+            config: config.to_string(),
         }))
+    } else if command == "editbot" && command_input.split_whitespace().count() > 2 {
+        let name = command_input.split_whitespace().nth(1).unwrap();
+        let rest = command_input.trim_start()[command.len()..].trim_start();
+        let (settings, config) = match BotSettings::parse(&rest[name.len()..]) {
+            Ok(parsed) => parsed,
+            Err(e) => return Some(Err(MessageParseError::InvalidBotSettings(e))),
+        };
+        Some(Ok(MessageCommand::EditBot {
+            name: name.to_string(),
+            settings,
+            config: (!config.is_empty()).then(|| config.to_string()),
+        }))
+    } else if let Some(action) = ModerationAction::from_command(command) {
+        let args = command_input.trim_start()[command.len()..].to_string();
+        match Moderation::parse(action, &args) {
+            Some(moderation) => Some(Ok(MessageCommand::Moderate(moderation))),
+            None => Some(Err(MessageParseError::InvalidCommand)),
+        }
     } else if command == "listbots" {
         Some(Ok(MessageCommand::ListBots))
     } else if command == "removebot" {
@@ -0,0 +1,250 @@
+//! Screening the messages users send before they are broadcast. A classifier,
+//! either a list of keywords or a model from one of the AI providers, decides
+//! whether a message needs an admin to look at it, and the policy of the room
+//! decides whether the message is flagged, hidden or held until an admin
+//! reviews it. Screening fails open: messages the classifier couldn't check
+//! are posted as usual.
+
+use std::time::Duration;
+
+use async_openai::{
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+        ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessage,
+        ChatCompletionRequestUserMessageContent, CreateChatCompletionRequestArgs,
+    },
+    Client,
+};
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    ai::{AiContext, DEFAULT_PROVIDER},
+    models::ScreenAction,
+    store::ChatStore,
+    usage,
+};
+
+/// What the model is told to do with every message
+const INSTRUCTIONS: &str = "You screen the messages sent to a public chat. \
+    Answer with only OK if the message is fine. If it has harassment, hate, \
+    threats, sexual content, spam or someone else's personal information in \
+    it, answer with FLAG: followed by a few words on why.";
+/// The name the tokens used for screening are recorded under
+const USAGE_NAME: &str = "Screening";
+/// The most tokens the model can answer with
+const MAX_ANSWER_TOKENS: u32 = 40;
+/// How long the model has to check a message if `AI_SCREENING_TIMEOUT_SECS`
+/// isn't set
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest reason kept from the model's answer
+const MAX_REASON_CHARS: usize = 200;
+
+enum Classifier {
+    Off,
+    /// Messages with any of the words in them need review
+    Keywords(Regex),
+    /// A model decides which messages need review
+    Model {
+        client: Client<OpenAIConfig>,
+        model: String,
+    },
+}
+
+pub struct Screening {
+    classifier: Classifier,
+    /// How long the model can take to check a message before it is posted
+    /// unchecked
+    timeout: Duration,
+}
+
+impl Screening {
+    /// Read the classifier from `AI_SCREENING`, which is `off`, `keywords` or
+    /// `llm`. Keywords come from `AI_SCREENING_KEYWORDS`, which is comma
+    /// separated, and `AI_SCREENING_KEYWORDS_FILE`, which has one keyword on
+    /// each line. The model is `AI_SCREENING_MODEL` of the provider
+    /// `AI_SCREENING_PROVIDER`, which has `AI_SCREENING_TIMEOUT_SECS` to
+    /// answer.
+    pub fn from_env(ai_context: &AiContext) -> Self {
+        let classifier = match std::env::var("AI_SCREENING").as_deref() {
+            Ok("keywords") => keywords_from_env(),
+            Ok("llm") => model_from_env(ai_context),
+            Ok("off") | Err(_) => Classifier::Off,
+            Ok(other) => {
+                log::error!("Unknown AI_SCREENING `{other}`, so messages aren't screened");
+                Classifier::Off
+            }
+        };
+        let timeout = std::env::var("AI_SCREENING_TIMEOUT_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map_or(DEFAULT_TIMEOUT, Duration::from_secs);
+        Self {
+            classifier,
+            timeout,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        !matches!(self.classifier, Classifier::Off)
+    }
+
+    /// Screen a public message in a thread, or in the main feed if `thread`
+    /// is `None`, getting what the room's policy does with it and why if the
+    /// classifier objects to it
+    pub async fn screen(
+        &self,
+        store: &dyn ChatStore,
+        thread: Option<i32>,
+        source: &str,
+    ) -> Option<(ScreenAction, String)> {
+        if !self.is_on() {
+            return None;
+        }
+        let room = match thread {
+            Some(thread) => match store.get_message_room(thread).await {
+                Ok(room) => room.map(|room| room.id),
+                Err(e) => {
+                    log::error!("Failed to load the room of message {thread}:\n{e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        let action = match store.screen_policies().await {
+            Ok(policies) => policies
+                .into_iter()
+                .find(|policy| policy.room == room)
+                .map(|policy| policy.action)
+                .unwrap_or_default(),
+            Err(e) => {
+                log::error!("Failed to load the screening policies:\n{e}");
+                ScreenAction::default()
+            }
+        };
+        if action == ScreenAction::Off {
+            return None;
+        }
+        let reason = self.check(store, source).await?;
+        Some((action, reason))
+    }
+
+    /// Check a message, getting why it needs review if it does
+    pub async fn check(&self, store: &dyn ChatStore, text: &str) -> Option<String> {
+        match &self.classifier {
+            Classifier::Off => None,
+            Classifier::Keywords(keywords) => keywords
+                .find(text)
+                .map(|found| format!("Has the keyword \"{}\"", found.as_str())),
+            Classifier::Model { client, model } => {
+                let request = CreateChatCompletionRequestArgs::default()
+                    .model(model)
+                    .temperature(0.0)
+                    .max_completion_tokens(MAX_ANSWER_TOKENS)
+                    .messages([
+                        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                            content: ChatCompletionRequestSystemMessageContent::Text(
+                                INSTRUCTIONS.to_string(),
+                            ),
+                            name: None,
+                        }),
+                        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                            content: ChatCompletionRequestUserMessageContent::Text(
+                                text.to_string(),
+                            ),
+                            name: None,
+                        }),
+                    ])
+                    .build()
+                    .unwrap();
+                let response =
+                    match tokio::time::timeout(self.timeout, client.chat().create(request)).await {
+                        Ok(Ok(response)) => response,
+                        Ok(Err(e)) => {
+                            log::error!("Failed to screen a message:\n{e}");
+                            return None;
+                        }
+                        Err(_) => {
+                            log::warn!("Screening a message timed out, so it is posted unchecked");
+                            return None;
+                        }
+                    };
+                if let Some(tokens) = &response.usage {
+                    if let Err(e) = usage::record(store, USAGE_NAME, "System", tokens).await {
+                        log::error!("Failed to record the tokens used for screening:\n{e}");
+                    }
+                }
+                let answer = response
+                    .choices
+                    .first()
+                    .and_then(|choice| choice.message.content.clone())
+                    .unwrap_or_default();
+                flagged_reason(&answer)
+            }
+        }
+    }
+}
+
+fn model_from_env(ai_context: &AiContext) -> Classifier {
+    let provider = std::env::var("AI_SCREENING_PROVIDER")
+        .map(|provider| provider.trim().to_lowercase())
+        .unwrap_or_else(|_| DEFAULT_PROVIDER.to_string());
+    let Some(client) = ai_context.client(&provider) else {
+        log::error!("Unknown AI_SCREENING_PROVIDER {provider}, so messages aren't screened");
+        return Classifier::Off;
+    };
+    let model = std::env::var("AI_SCREENING_MODEL")
+        .unwrap_or_else(|_| ai_context.default_model().to_string());
+    Classifier::Model { client, model }
+}
+
+fn keywords_from_env() -> Classifier {
+    let mut words = std::env::var("AI_SCREENING_KEYWORDS")
+        .map(|list| list.split(',').map(str::to_string).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let Ok(path) = std::env::var("AI_SCREENING_KEYWORDS_FILE") {
+        match std::fs::read_to_string(&path) {
+            Ok(file) => words.extend(file.lines().map(str::to_string)),
+            Err(e) => log::error!("Failed to read the screening keywords {path}:\n{e}"),
+        }
+    }
+    let words = words
+        .iter()
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty())
+        .map(regex::escape)
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        log::error!("No screening keywords are set, so messages aren't screened");
+        return Classifier::Off;
+    }
+    // Keywords only match whole words, so "ass" doesn't match "class"
+    match RegexBuilder::new(&format!(r"\b(?:{})\b", words.join("|")))
+        .case_insensitive(true)
+        .build()
+    {
+        Ok(keywords) => Classifier::Keywords(keywords),
+        Err(e) => {
+            log::error!("Failed to compile the screening keywords:\n{e}");
+            Classifier::Off
+        }
+    }
+}
+
+/// Read the model's answer, getting its reason if it flagged the message.
+/// Answers that are neither OK nor a flag are taken as OK.
+fn flagged_reason(answer: &str) -> Option<String> {
+    let answer = answer.trim();
+    let rest = answer
+        .get(..4)
+        .filter(|start| start.eq_ignore_ascii_case("flag"))
+        .map(|_| &answer[4..])?;
+    let reason = rest
+        .trim_start_matches(|c: char| c.is_alphabetic())
+        .trim_start_matches([':', ' '])
+        .trim();
+    if reason.is_empty() {
+        return Some("Flagged by the classifier".to_string());
+    }
+    Some(reason.chars().take(MAX_REASON_CHARS).collect())
+}
@@ -0,0 +1,580 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+
+use super::{query_words, ChatStore, SearchFilter, MAX_DIRECT_MESSAGES};
+use crate::{
+    ai::Bot,
+    models::{
+        user_key, AiUsage, Attachment, AuditEntry, LinkPreview, Message, Reaction, RetentionPolicy,
+        Review, Room, Sanction, SanctionKind, ScreenAction, ScreenPolicy, User,
+    },
+};
+
+/// A store that keeps everything in memory, so nothing outlives the server
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryData>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    messages: BTreeMap<i32, Message>,
+    last_id: i32,
+    /// The message, user and emoji of every reaction, in the order they were
+    /// added
+    reactions: Vec<(i32, String, String)>,
+    /// The room and message of every message sent in a room
+    room_messages: Vec<(i32, i32)>,
+    attachments: Vec<Attachment>,
+    link_previews: HashMap<String, LinkPreview>,
+    sanctions: Vec<Sanction>,
+    /// The audit log, oldest first
+    moderation_log: Vec<AuditEntry>,
+    ai_usage: Vec<AiUsage>,
+    rooms: Vec<Room>,
+    retention_policies: Vec<RetentionPolicy>,
+    screen_policies: Vec<ScreenPolicy>,
+    reviews: Vec<Review>,
+    last_review_id: i32,
+    users: HashMap<String, User>,
+    /// The session that has each name, by the key of the name
+    name_claims: HashMap<String, String>,
+    bots: Vec<Bot>,
+}
+
+impl MemoryStore {
+    fn data(&self) -> MutexGuard<'_, MemoryData> {
+        // Nothing panics while holding the lock, so the data can't be left
+        // half updated
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MemoryData {
+    /// Get a message as it would come out of a database
+    fn message(&self, message: &Message) -> Message {
+        Message {
+            reply_count: self.count_replies(message.id.unwrap_or_default()),
+            should_notify: false,
+            reactions: vec![],
+            attachments: vec![],
+            previews: vec![],
+            ..message.clone()
+        }
+    }
+
+    /// Whether a message is in a room, or in no room if `room` is `None`.
+    /// Replies are in the room of their thread.
+    fn is_in_room(&self, message: &Message, room: Option<i32>) -> bool {
+        let thread = message.parent_id.or(message.id);
+        let mut rooms = self
+            .room_messages
+            .iter()
+            .filter(|(_, m)| Some(*m) == thread)
+            .map(|(room, _)| *room);
+        match room {
+            Some(room) => rooms.any(|r| r == room),
+            None => rooms.next().is_none(),
+        }
+    }
+
+    fn count_replies(&self, id: i32) -> i64 {
+        self.messages
+            .values()
+            .filter(|message| message.parent_id == Some(id))
+            .count() as i64
+    }
+
+    fn reactions(&self, message: i32) -> Vec<Reaction> {
+        let mut reactions: Vec<Reaction> = vec![];
+        for (_, user, emoji) in self.reactions.iter().filter(|(m, ..)| *m == message) {
+            match reactions
+                .iter_mut()
+                .find(|reaction| reaction.emoji == *emoji)
+            {
+                Some(reaction) => reaction.users.push(user.clone()),
+                None => reactions.push(Reaction {
+                    emoji: emoji.clone(),
+                    users: vec![user.clone()],
+                }),
+            }
+        }
+        reactions
+    }
+}
+
+#[async_trait]
+impl ChatStore for MemoryStore {
+    async fn insert_message(&self, message: &Message) -> sqlx::Result<i32> {
+        let mut data = self.data();
+        data.last_id += 1;
+        let id = data.last_id;
+        data.messages.insert(
+            id,
+            Message {
+                id: Some(id),
+                ..message.clone()
+            },
+        );
+        Ok(id)
+    }
+
+    async fn get_message(&self, id: i32) -> sqlx::Result<Option<Message>> {
+        let data = self.data();
+        Ok(data.messages.get(&id).map(|message| data.message(message)))
+    }
+
+    async fn get_replies(&self, id: i32) -> sqlx::Result<Vec<Message>> {
+        let data = self.data();
+        let mut replies = data
+            .messages
+            .values()
+            .filter(|message| message.parent_id == Some(id))
+            .map(|message| data.message(message))
+            .collect::<Vec<_>>();
+        replies.sort_by_key(|message| (message.sent_date, message.id));
+        Ok(replies)
+    }
+
+    async fn get_threads(&self, room: Option<i32>) -> sqlx::Result<Vec<Message>> {
+        let data = self.data();
+        let mut threads = data
+            .messages
+            .values()
+            .filter(|message| {
+                message.parent_id.is_none()
+                    && message.recipient.is_none()
+                    && data.is_in_room(message, room)
+            })
+            .map(|message| data.message(message))
+            .collect::<Vec<_>>();
+        threads.sort_by_key(|message| (message.sent_date, message.id));
+        Ok(threads)
+    }
+
+    async fn latest_messages(&self, room: Option<i32>, limit: i64) -> sqlx::Result<Vec<Message>> {
+        let data = self.data();
+        let mut messages = data
+            .messages
+            .values()
+            .filter(|message| message.recipient.is_none() && data.is_in_room(message, room))
+            .map(|message| data.message(message))
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|message| (message.sent_date, message.id));
+        let limit = limit.try_into().unwrap_or_default();
+        Ok(messages.split_off(messages.len().saturating_sub(limit)))
+    }
+
+    async fn add_to_room(&self, room: i32, message: i32) -> sqlx::Result<()> {
+        self.data().room_messages.push((room, message));
+        Ok(())
+    }
+
+    async fn get_message_room(&self, message: i32) -> sqlx::Result<Option<Room>> {
+        let data = self.data();
+        Ok(data
+            .room_messages
+            .iter()
+            .find(|(_, room_message)| *room_message == message)
+            .and_then(|(room, _)| data.rooms.iter().find(|r| r.id == *room))
+            .cloned())
+    }
+
+    async fn get_direct_messages(&self, user: &str, other: &str) -> sqlx::Result<Vec<Message>> {
+        let data = self.data();
+        let mut messages = data
+            .messages
+            .values()
+            .filter(|message| {
+                let sender = user_key(&message.sender);
+                match message.recipient.as_deref() {
+                    Some(recipient) => {
+                        (sender == user && recipient == other)
+                            || (sender == other && recipient == user)
+                    }
+                    None => false,
+                }
+            })
+            .map(|message| data.message(message))
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|message| std::cmp::Reverse((message.sent_date, message.id)));
+        messages.truncate(MAX_DIRECT_MESSAGES as usize);
+        Ok(messages)
+    }
+
+    async fn count_replies(&self, id: i32) -> sqlx::Result<i64> {
+        Ok(self.data().count_replies(id))
+    }
+
+    async fn update_message(&self, message: &Message) -> sqlx::Result<()> {
+        let mut data = self.data();
+        if let Some(stored) = message.id.and_then(|id| data.messages.get_mut(&id)) {
+            stored.contents = message.contents.clone();
+            stored.source = message.source.clone();
+            stored.edited_at = message.edited_at;
+            stored.hidden = message.hidden;
+        }
+        Ok(())
+    }
+
+    async fn delete_message(&self, id: i32) -> sqlx::Result<Vec<String>> {
+        let mut data = self.data();
+        data.messages
+            .retain(|_, message| message.id != Some(id) && message.parent_id != Some(id));
+        let MemoryData {
+            messages,
+            reactions,
+            room_messages,
+            attachments,
+            reviews,
+            ..
+        } = &mut *data;
+        reactions.retain(|(message, ..)| messages.contains_key(message));
+        reviews.retain(|review| {
+            [review.message, review.parent_id]
+                .into_iter()
+                .flatten()
+                .all(|message| messages.contains_key(&message))
+        });
+        room_messages.retain(|(_, message)| messages.contains_key(message));
+        let (kept, deleted): (Vec<_>, Vec<_>) = std::mem::take(attachments)
+            .into_iter()
+            .partition(|attachment| messages.contains_key(&attachment.message));
+        *attachments = kept;
+        Ok(deleted
+            .into_iter()
+            .map(|attachment| attachment.key)
+            .collect())
+    }
+
+    async fn set_pinned(&self, id: i32, pinned: bool) -> sqlx::Result<()> {
+        if let Some(message) = self.data().messages.get_mut(&id) {
+            message.pinned = pinned;
+        }
+        Ok(())
+    }
+
+    async fn retention_candidates(&self, policy: &RetentionPolicy) -> sqlx::Result<Vec<Message>> {
+        let data = self.data();
+        let mut threads = data
+            .messages
+            .values()
+            .filter(|message| {
+                message.parent_id.is_none()
+                    && !message.pinned
+                    && !data
+                        .messages
+                        .values()
+                        .any(|reply| reply.parent_id == message.id && reply.pinned)
+                    && data.is_in_room(message, policy.room)
+            })
+            .collect::<Vec<_>>();
+        threads.sort_by_key(|message| std::cmp::Reverse((message.sent_date, message.id)));
+        let cutoff = policy.cutoff();
+        let max_count = policy
+            .max_count
+            .map(|count| count.try_into().unwrap_or_default());
+        let mut candidates = threads
+            .into_iter()
+            .enumerate()
+            .filter(|(position, message)| {
+                cutoff.is_some_and(|cutoff| message.sent_date < cutoff)
+                    || max_count.is_some_and(|count| *position >= count)
+            })
+            .map(|(_, message)| data.message(message))
+            .collect::<Vec<_>>();
+        candidates.reverse();
+        Ok(candidates)
+    }
+
+    async fn search_messages(
+        &self,
+        filter: &SearchFilter,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let words = query_words(&filter.query);
+        if words.is_empty() {
+            return Ok(vec![]);
+        }
+        let data = self.data();
+        let mut results = data
+            .messages
+            .values()
+            .filter(|message| {
+                let source = message.source.to_lowercase();
+                let sender = user_key(&message.sender);
+                words.iter().all(|word| source.contains(word))
+                    && !source.starts_with('!')
+                    && filter.sender.as_ref().is_none_or(|key| *key == sender)
+                    && filter
+                        .room
+                        .is_none_or(|room| data.is_in_room(message, Some(room)))
+                    && filter.from.is_none_or(|from| message.sent_date >= from)
+                    && filter.until.is_none_or(|until| message.sent_date < until)
+                    && match &message.recipient {
+                        Some(recipient) => filter
+                            .viewer
+                            .as_ref()
+                            .is_some_and(|viewer| *viewer == *recipient || *viewer == sender),
+                        None => true,
+                    }
+            })
+            .map(|message| data.message(message))
+            .collect::<Vec<_>>();
+        results.sort_by_key(|message| std::cmp::Reverse(message.sent_date));
+        results.truncate(limit.try_into().unwrap_or_default());
+        Ok(results)
+    }
+
+    async fn get_reactions(&self, message: i32) -> sqlx::Result<Vec<Reaction>> {
+        Ok(self.data().reactions(message))
+    }
+
+    async fn toggle_reaction(
+        &self,
+        message: i32,
+        user: &str,
+        emoji: &str,
+    ) -> sqlx::Result<Vec<Reaction>> {
+        let mut data = self.data();
+        let reaction = (message, user.to_string(), emoji.to_string());
+        match data.reactions.iter().position(|r| *r == reaction) {
+            Some(index) => {
+                data.reactions.remove(index);
+            }
+            None => data.reactions.push(reaction),
+        }
+        Ok(data.reactions(message))
+    }
+
+    async fn add_attachment(&self, attachment: &Attachment) -> sqlx::Result<()> {
+        self.data().attachments.push(attachment.clone());
+        Ok(())
+    }
+
+    async fn get_attachments(&self, message: i32) -> sqlx::Result<Vec<Attachment>> {
+        Ok(self
+            .data()
+            .attachments
+            .iter()
+            .filter(|attachment| attachment.message == message)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_attachment(&self, key: &str) -> sqlx::Result<Option<Attachment>> {
+        Ok(self
+            .data()
+            .attachments
+            .iter()
+            .find(|attachment| attachment.key == key)
+            .cloned())
+    }
+
+    async fn get_link_preview(&self, url: &str) -> sqlx::Result<Option<LinkPreview>> {
+        Ok(self.data().link_previews.get(url).cloned())
+    }
+
+    async fn save_link_preview(&self, preview: &LinkPreview) -> sqlx::Result<()> {
+        self.data()
+            .link_previews
+            .insert(preview.url.clone(), preview.clone());
+        Ok(())
+    }
+
+    async fn active_sanctions(&self, user: Option<&str>) -> sqlx::Result<Vec<Sanction>> {
+        let now = Utc::now();
+        Ok(self
+            .data()
+            .sanctions
+            .iter()
+            .filter(|sanction| user.is_none_or(|user| sanction.user_key == user))
+            .filter(|sanction| {
+                sanction
+                    .expires_at
+                    .is_none_or(|expires_at| expires_at > now)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn add_sanction(&self, sanction: &Sanction) -> sqlx::Result<()> {
+        let mut data = self.data();
+        data.sanctions.retain(|existing| {
+            existing.user_key != sanction.user_key || existing.kind != sanction.kind
+        });
+        data.sanctions.push(sanction.clone());
+        Ok(())
+    }
+
+    async fn remove_sanction(&self, user: &str, kind: SanctionKind) -> sqlx::Result<bool> {
+        let mut data = self.data();
+        let Some(index) = data
+            .sanctions
+            .iter()
+            .position(|sanction| sanction.user_key == user && sanction.kind == kind)
+        else {
+            return Ok(false);
+        };
+        let removed = data.sanctions.remove(index);
+        Ok(removed
+            .expires_at
+            .is_none_or(|expires_at| expires_at > Utc::now()))
+    }
+
+    async fn log_moderation(&self, entry: &AuditEntry) -> sqlx::Result<()> {
+        self.data().moderation_log.push(entry.clone());
+        Ok(())
+    }
+
+    async fn moderation_log(&self, limit: i64) -> sqlx::Result<Vec<AuditEntry>> {
+        Ok(self
+            .data()
+            .moderation_log
+            .iter()
+            .rev()
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn record_ai_usage(&self, usage: &AiUsage) -> sqlx::Result<()> {
+        let mut data = self.data();
+        let existing = data.ai_usage.iter_mut().find(|existing| {
+            existing.day == usage.day
+                && existing.bot == usage.bot
+                && existing.user_key == usage.user_key
+        });
+        match existing {
+            Some(existing) => {
+                existing.prompt_tokens += usage.prompt_tokens;
+                existing.completion_tokens += usage.completion_tokens;
+                existing.requests += usage.requests;
+            }
+            None => data.ai_usage.push(usage.clone()),
+        }
+        Ok(())
+    }
+
+    async fn ai_usage(&self, since: NaiveDate, user: Option<&str>) -> sqlx::Result<Vec<AiUsage>> {
+        let mut usage = self
+            .data()
+            .ai_usage
+            .iter()
+            .filter(|usage| usage.day >= since)
+            .filter(|usage| user.is_none_or(|user| usage.user_key == user))
+            .cloned()
+            .collect::<Vec<_>>();
+        usage.sort_by(|a, b| {
+            b.day
+                .cmp(&a.day)
+                .then_with(|| a.bot.cmp(&b.bot))
+                .then_with(|| a.user_key.cmp(&b.user_key))
+        });
+        Ok(usage)
+    }
+
+    async fn rooms(&self) -> sqlx::Result<Vec<Room>> {
+        Ok(self.data().rooms.clone())
+    }
+
+    async fn retention_policies(&self) -> sqlx::Result<Vec<RetentionPolicy>> {
+        let mut policies = self.data().retention_policies.clone();
+        policies.sort_by_key(|policy| policy.room);
+        Ok(policies)
+    }
+
+    async fn set_retention_policy(&self, policy: &RetentionPolicy) -> sqlx::Result<()> {
+        let mut data = self.data();
+        data.retention_policies.retain(|p| p.room != policy.room);
+        if !policy.is_unlimited() {
+            data.retention_policies.push(policy.clone());
+        }
+        Ok(())
+    }
+
+    async fn screen_policies(&self) -> sqlx::Result<Vec<ScreenPolicy>> {
+        let mut policies = self.data().screen_policies.clone();
+        policies.sort_by_key(|policy| policy.room);
+        Ok(policies)
+    }
+
+    async fn set_screen_policy(&self, policy: &ScreenPolicy) -> sqlx::Result<()> {
+        let mut data = self.data();
+        data.screen_policies.retain(|p| p.room != policy.room);
+        if policy.action != ScreenAction::default() {
+            data.screen_policies.push(policy.clone());
+        }
+        Ok(())
+    }
+
+    async fn add_review(&self, review: &Review) -> sqlx::Result<i32> {
+        let mut data = self.data();
+        data.last_review_id += 1;
+        let id = data.last_review_id;
+        data.reviews.push(Review {
+            id,
+            ..review.clone()
+        });
+        Ok(id)
+    }
+
+    async fn reviews(&self) -> sqlx::Result<Vec<Review>> {
+        Ok(self.data().reviews.clone())
+    }
+
+    async fn get_review(&self, id: i32) -> sqlx::Result<Option<Review>> {
+        Ok(self.data().reviews.iter().find(|r| r.id == id).cloned())
+    }
+
+    async fn delete_review(&self, id: i32) -> sqlx::Result<()> {
+        self.data().reviews.retain(|r| r.id != id);
+        Ok(())
+    }
+
+    async fn record_user(&self, name: &str) -> sqlx::Result<()> {
+        let user = User {
+            key: user_key(name),
+            name: name.to_string(),
+            last_seen: Utc::now(),
+        };
+        self.data().users.insert(user.key.clone(), user);
+        Ok(())
+    }
+
+    async fn get_user(&self, key: &str) -> sqlx::Result<Option<User>> {
+        Ok(self.data().users.get(key).cloned())
+    }
+
+    async fn claim_name(&self, name: &str, session: &str) -> sqlx::Result<bool> {
+        let mut data = self.data();
+        let owner = data
+            .name_claims
+            .entry(user_key(name))
+            .or_insert_with(|| session.to_string());
+        Ok(owner == session)
+    }
+
+    async fn bots(&self) -> sqlx::Result<Vec<Bot>> {
+        Ok(self.data().bots.clone())
+    }
+
+    async fn save_bot(&self, bot: &Bot) -> sqlx::Result<()> {
+        let mut data = self.data();
+        match data.bots.iter_mut().find(|b| b.name() == bot.name()) {
+            Some(stored) => *stored = bot.clone(),
+            None => data.bots.push(bot.clone()),
+        }
+        Ok(())
+    }
+
+    async fn delete_bot(&self, name: &str) -> sqlx::Result<()> {
+        self.data().bots.retain(|bot| bot.name() != name);
+        Ok(())
+    }
+}
@@ -0,0 +1,678 @@
+//! Storage for everything the chat keeps: messages, reactions, link
+//! previews, rooms, users, sanctions and bots. The files of attachments are
+//! kept in a [`FileStorage`](crate::attachments::FileStorage) instead.
+//! Handlers only see the [`ChatStore`] trait, so the backend is picked once
+//! at startup by the scheme of the database URL. Postgres and SQLite share
+//! most of their queries, which are in [`sql`], but each has its own
+//! migrations, since the types they support differ too much to share them.
+//! Their migrations are numbered in step, so every change to the schema has a
+//! migration with the same number in both.
+
+mod memory;
+mod postgres;
+mod sql;
+mod sqlite;
+
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool},
+    PgPool,
+};
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+use crate::{
+    ai::Bot,
+    models::{
+        AiUsage, Attachment, AuditEntry, LinkPreview, Message, Reaction, RetentionPolicy, Review,
+        Room, Sanction, SanctionKind, ScreenPolicy, User,
+    },
+};
+
+/// The most direct messages shown between two users
+const MAX_DIRECT_MESSAGES: i64 = 200;
+
+#[async_trait]
+pub trait ChatStore: Send + Sync {
+    /// Save a new message, returning its id
+    async fn insert_message(&self, message: &Message) -> sqlx::Result<i32>;
+    async fn get_message(&self, id: i32) -> sqlx::Result<Option<Message>>;
+    /// Get the replies to a message, oldest first
+    async fn get_replies(&self, id: i32) -> sqlx::Result<Vec<Message>>;
+    /// Get the first messages of the threads in a room, or in no room if
+    /// `room` is `None`, oldest first. Direct messages are left out.
+    async fn get_threads(&self, room: Option<i32>) -> sqlx::Result<Vec<Message>>;
+    /// Get the latest messages in a room, or in no room if `room` is `None`,
+    /// oldest first. Unlike [`ChatStore::get_threads`] replies are included.
+    async fn latest_messages(&self, room: Option<i32>, limit: i64) -> sqlx::Result<Vec<Message>>;
+    async fn add_to_room(&self, room: i32, message: i32) -> sqlx::Result<()>;
+    /// Get the room a thread was started in, given its first message
+    async fn get_message_room(&self, message: i32) -> sqlx::Result<Option<Room>>;
+    /// Get the direct messages between two users, newest first. Both users
+    /// are given by their keys.
+    async fn get_direct_messages(&self, user: &str, other: &str) -> sqlx::Result<Vec<Message>>;
+    async fn count_replies(&self, id: i32) -> sqlx::Result<i64>;
+    /// Save the contents and edit time of a message
+    async fn update_message(&self, message: &Message) -> sqlx::Result<()>;
+    /// Delete a message along with its replies, reactions and attachments,
+    /// returning the keys of the attachments so their files can be deleted
+    async fn delete_message(&self, id: i32) -> sqlx::Result<Vec<String>>;
+    async fn set_pinned(&self, id: i32, pinned: bool) -> sqlx::Result<()>;
+    /// Get the messages a retention policy would delete now, oldest first.
+    /// Their replies are deleted along with them, so threads with a pinned
+    /// reply are kept like pinned messages.
+    async fn retention_candidates(&self, policy: &RetentionPolicy) -> sqlx::Result<Vec<Message>>;
+    /// Search messages, best matches first. Commands are left out.
+    async fn search_messages(
+        &self,
+        filter: &SearchFilter,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>>;
+
+    async fn get_reactions(&self, message: i32) -> sqlx::Result<Vec<Reaction>>;
+    /// Add the reaction if the user hasn't reacted with that emoji yet,
+    /// otherwise remove it. Returns the reactions to the message after the
+    /// change.
+    async fn toggle_reaction(
+        &self,
+        message: i32,
+        user: &str,
+        emoji: &str,
+    ) -> sqlx::Result<Vec<Reaction>>;
+
+    async fn add_attachment(&self, attachment: &Attachment) -> sqlx::Result<()>;
+    /// Get the attachments of a message in the order they were added
+    async fn get_attachments(&self, message: i32) -> sqlx::Result<Vec<Attachment>>;
+    async fn get_attachment(&self, key: &str) -> sqlx::Result<Option<Attachment>>;
+
+    async fn get_link_preview(&self, url: &str) -> sqlx::Result<Option<LinkPreview>>;
+    /// Save the preview of a link, replacing any earlier preview of it
+    async fn save_link_preview(&self, preview: &LinkPreview) -> sqlx::Result<()>;
+
+    /// Get the sanctions that haven't expired, of one user given by their key
+    /// or of every user
+    async fn active_sanctions(&self, user: Option<&str>) -> sqlx::Result<Vec<Sanction>>;
+    /// Save a sanction, replacing the user's earlier sanction of the same kind
+    async fn add_sanction(&self, sanction: &Sanction) -> sqlx::Result<()>;
+    /// Lift a sanction, returning whether the user had one that hadn't
+    /// expired
+    async fn remove_sanction(&self, user: &str, kind: SanctionKind) -> sqlx::Result<bool>;
+    async fn log_moderation(&self, entry: &AuditEntry) -> sqlx::Result<()>;
+    /// Get the latest entries of the audit log, newest first
+    async fn moderation_log(&self, limit: i64) -> sqlx::Result<Vec<AuditEntry>>;
+
+    /// Add the tokens used for a bot response to the usage of its bot and user
+    /// on its day
+    async fn record_ai_usage(&self, usage: &AiUsage) -> sqlx::Result<()>;
+    /// Get the usage on every day since `since`, of one user given by their
+    /// key or of every user, newest first
+    async fn ai_usage(&self, since: NaiveDate, user: Option<&str>) -> sqlx::Result<Vec<AiUsage>>;
+
+    async fn rooms(&self) -> sqlx::Result<Vec<Room>>;
+    async fn retention_policies(&self) -> sqlx::Result<Vec<RetentionPolicy>>;
+    /// Replace the retention policy of a room, removing it if it has no
+    /// limits
+    async fn set_retention_policy(&self, policy: &RetentionPolicy) -> sqlx::Result<()>;
+    async fn screen_policies(&self) -> sqlx::Result<Vec<ScreenPolicy>>;
+    /// Replace the screening policy of a room, removing it if it's the
+    /// default
+    async fn set_screen_policy(&self, policy: &ScreenPolicy) -> sqlx::Result<()>;
+
+    /// Add a message to the review queue, returning the id of the review
+    async fn add_review(&self, review: &Review) -> sqlx::Result<i32>;
+    /// Get the review queue, oldest first
+    async fn reviews(&self) -> sqlx::Result<Vec<Review>>;
+    async fn get_review(&self, id: i32) -> sqlx::Result<Option<Review>>;
+    async fn delete_review(&self, id: i32) -> sqlx::Result<()>;
+
+    /// Remember that a user connected with a name
+    async fn record_user(&self, name: &str) -> sqlx::Result<()>;
+    /// Get a user by their key
+    async fn get_user(&self, key: &str) -> sqlx::Result<Option<User>>;
+    /// Give a name to a session if no other session has it yet, returning
+    /// whether the session has it
+    async fn claim_name(&self, name: &str, session: &str) -> sqlx::Result<bool>;
+
+    /// Get all bots, oldest first
+    async fn bots(&self) -> sqlx::Result<Vec<Bot>>;
+    /// Save a new bot or the changes to an existing bot with the same name
+    async fn save_bot(&self, bot: &Bot) -> sqlx::Result<()>;
+    async fn delete_bot(&self, name: &str) -> sqlx::Result<()>;
+}
+
+/// Connect to the store at a database URL: `postgres://` and `sqlite://`
+/// URLs are databases, which are migrated before they are used, and
+/// `memory:` keeps everything in memory until the server stops. SQLite
+/// databases are created if they don't exist yet.
+pub async fn connect(url: &str) -> anyhow::Result<Arc<dyn ChatStore>> {
+    if url == "memory:" {
+        Ok(Arc::new(MemoryStore::default()))
+    } else if url.starts_with("sqlite:") {
+        let options = SqliteConnectOptions::from_str(url)
+            .context("Invalid SQLite database URL")?
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .context("Failed to open the SQLite database")?;
+        Ok(Arc::new(SqliteStore::new(pool).await?))
+    } else {
+        let pool = PgPool::connect(url)
+            .await
+            .context("Failed to connect to the Postgres database")?;
+        Ok(Arc::new(PostgresStore::new(pool).await?))
+    }
+}
+
+pub struct SearchFilter {
+    /// The search as typed by the user. Postgres understands the
+    /// `websearch_to_tsquery` syntax, the other backends only search for the
+    /// words.
+    pub query: String,
+    /// The key of the user who sent the messages
+    pub sender: Option<String>,
+    pub room: Option<i32>,
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// The key of the user searching, whose direct messages are included. If
+    /// this is `None` only public messages are searched.
+    pub viewer: Option<String>,
+}
+
+/// Get the words of a search in lowercase, for the backends that only
+/// search for words
+fn query_words(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Bots are stored as JSON, since most of a bot is its message history
+fn encode_bot(bot: &Bot) -> sqlx::Result<String> {
+    serde_json::to_string(bot)
+        .map_err(|e| sqlx::Error::Protocol(format!("Failed to encode bot: {e}")))
+}
+
+fn decode_bot(data: &str) -> sqlx::Result<Bot> {
+    serde_json::from_str(data).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use chrono::Duration;
+    use sqlx::{
+        migrate::Migrator,
+        postgres::{PgPool, PgPoolOptions},
+        sqlite::SqlitePoolOptions,
+        Executor,
+    };
+
+    use super::*;
+
+    /// An empty in-memory SQLite database
+    async fn sqlite_pool() -> SqlitePool {
+        // Every connection to an in-memory database has its own database, so
+        // the pool keeps a single connection open
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    /// An empty schema in the Postgres database at `DATABASE_URL`, if it is
+    /// set to one. The schema is made again every time, so what a test left
+    /// in it is only kept until it runs again.
+    async fn postgres_pool(schema: &str) -> Option<PgPool> {
+        let url = std::env::var("DATABASE_URL")
+            .ok()
+            .filter(|url| url.starts_with("postgres:"))?;
+        PgPool::connect(&url)
+            .await
+            .unwrap()
+            .execute(
+                format!("DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}").as_str(),
+            )
+            .await
+            .unwrap();
+        let search_path = format!("SET search_path TO {schema}");
+        let pool = PgPoolOptions::new()
+            .after_connect(move |connection, _| {
+                let search_path = search_path.clone();
+                Box::pin(async move {
+                    connection.execute(search_path.as_str()).await?;
+                    Ok(())
+                })
+            })
+            .connect(&url)
+            .await
+            .unwrap();
+        Some(pool)
+    }
+
+    /// Every backend, with Postgres only if `DATABASE_URL` is set to it. Its
+    /// store is kept in a schema named after the test.
+    async fn stores(test: &str) -> Vec<(&'static str, Arc<dyn ChatStore>)> {
+        // Rooms are only made by hand, and messages can only be added to ones
+        // that exist
+        const ADD_ROOM: &str =
+            "INSERT INTO rooms (id, name, description) VALUES (1, 'Launches', '')";
+        let pool = sqlite_pool().await;
+        let sqlite = SqliteStore::new(pool.clone()).await.unwrap();
+        sqlx::query(ADD_ROOM).execute(&pool).await.unwrap();
+        let mut stores: Vec<(&'static str, Arc<dyn ChatStore>)> = vec![
+            ("memory", Arc::new(MemoryStore::default())),
+            ("sqlite", Arc::new(sqlite)),
+        ];
+        if let Some(pool) = postgres_pool(&format!("test_{test}")).await {
+            let postgres = PostgresStore::new(pool.clone()).await.unwrap();
+            sqlx::query(ADD_ROOM).execute(&pool).await.unwrap();
+            stores.push(("postgres", Arc::new(postgres)));
+        }
+        stores
+    }
+
+    fn message(sender: &str, source: &str, parent_id: Option<i32>, minutes_ago: i64) -> Message {
+        Message {
+            id: None,
+            parent_id,
+            sender: sender.to_string(),
+            recipient: None,
+            sent_date: Utc::now() - Duration::minutes(minutes_ago),
+            edited_at: None,
+            contents: format!("<p>{source}</p>"),
+            source: source.to_string(),
+            pinned: false,
+            hidden: false,
+            session: None,
+            should_notify: true,
+            reply_count: 0,
+            reactions: vec![],
+            attachments: vec![],
+            previews: vec![],
+        }
+    }
+
+    fn attachment(message: i32, key: &str) -> Attachment {
+        Attachment {
+            message,
+            key: key.to_string(),
+            filename: format!("{key}.txt"),
+            content_type: "text/plain".to_string(),
+            size: 4,
+            has_thumbnail: false,
+        }
+    }
+
+    fn search(query: &str) -> SearchFilter {
+        SearchFilter {
+            query: query.to_string(),
+            sender: None,
+            room: None,
+            from: None,
+            until: None,
+            viewer: None,
+        }
+    }
+
+    fn sources(messages: &[Message]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.source.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn inserts_messages() {
+        for (name, store) in stores("inserts_messages").await {
+            let id = store
+                .insert_message(&Message {
+                    session: Some("abc".to_string()),
+                    ..message("Alice", "hello there", None, 5)
+                })
+                .await
+                .unwrap();
+            let dm = Message {
+                recipient: Some("bob".to_string()),
+                ..message("Alice", "psst", None, 4)
+            };
+            store.insert_message(&dm).await.unwrap();
+
+            let stored = store.get_message(id).await.unwrap().expect(name);
+            assert_eq!(stored.id, Some(id), "{name}");
+            assert_eq!(stored.sender, "Alice", "{name}");
+            assert_eq!(stored.source, "hello there", "{name}");
+            assert_eq!(stored.contents, "<p>hello there</p>", "{name}");
+            assert!(stored.is_sent_from(Some("abc")), "{name}");
+            assert!(!stored.is_sent_from(None), "{name}");
+            assert!(
+                store.get_message(id + 100).await.unwrap().is_none(),
+                "{name}"
+            );
+            let threads = store.get_threads(None).await.unwrap();
+            assert_eq!(sources(&threads), ["hello there"], "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn edits_messages() {
+        for (name, store) in stores("edits_messages").await {
+            let id = store
+                .insert_message(&message("Alice", "first draft", None, 5))
+                .await
+                .unwrap();
+            let stored = store.get_message(id).await.unwrap().unwrap();
+            let edited_at = Utc::now();
+            store
+                .update_message(&Message {
+                    contents: "<p>second draft</p>".to_string(),
+                    source: "second draft".to_string(),
+                    edited_at: Some(edited_at),
+                    hidden: true,
+                    ..stored
+                })
+                .await
+                .unwrap();
+
+            let stored = store.get_message(id).await.unwrap().unwrap();
+            assert_eq!(stored.source, "second draft", "{name}");
+            assert_eq!(stored.contents, "<p>second draft</p>", "{name}");
+            assert!(stored.edited_at.is_some(), "{name}");
+            assert!(stored.hidden, "{name}");
+            let found = store.search_messages(&search("second"), 10).await.unwrap();
+            assert_eq!(sources(&found), ["second draft"], "{name}");
+            let found = store.search_messages(&search("first"), 10).await.unwrap();
+            assert!(found.is_empty(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_replies_in_their_thread() {
+        for (name, store) in stores("keeps_replies_in_their_thread").await {
+            let thread = store
+                .insert_message(&message("Alice", "question", None, 10))
+                .await
+                .unwrap();
+            let other = store
+                .insert_message(&message("Carol", "other thread", None, 9))
+                .await
+                .unwrap();
+            for (source, minutes_ago) in [("first answer", 8), ("second answer", 7)] {
+                store
+                    .insert_message(&message("Bob", source, Some(thread), minutes_ago))
+                    .await
+                    .unwrap();
+            }
+
+            let replies = store.get_replies(thread).await.unwrap();
+            assert_eq!(
+                sources(&replies),
+                ["first answer", "second answer"],
+                "{name}"
+            );
+            assert_eq!(store.count_replies(thread).await.unwrap(), 2, "{name}");
+            assert_eq!(store.count_replies(other).await.unwrap(), 0, "{name}");
+            let stored = store.get_message(thread).await.unwrap().unwrap();
+            assert_eq!(stored.reply_count, 2, "{name}");
+            let threads = store.get_threads(None).await.unwrap();
+            assert_eq!(sources(&threads), ["question", "other thread"], "{name}");
+            let latest = store.latest_messages(None, 3).await.unwrap();
+            assert_eq!(
+                sources(&latest),
+                ["other thread", "first answer", "second answer"],
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn toggles_reactions() {
+        for (name, store) in stores("toggles_reactions").await {
+            let id = store
+                .insert_message(&message("Alice", "news", None, 5))
+                .await
+                .unwrap();
+            store.toggle_reaction(id, "alice", "👍").await.unwrap();
+            let reactions = store.toggle_reaction(id, "bob", "👍").await.unwrap();
+            assert_eq!(reactions.len(), 1, "{name}");
+            assert_eq!(reactions[0].emoji, "👍", "{name}");
+            assert_eq!(reactions[0].users, ["alice", "bob"], "{name}");
+
+            let reactions = store.toggle_reaction(id, "alice", "👍").await.unwrap();
+            assert_eq!(reactions[0].users, ["bob"], "{name}");
+            store.toggle_reaction(id, "bob", "👍").await.unwrap();
+            assert!(store.get_reactions(id).await.unwrap().is_empty(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn deletes_threads_with_everything_in_them() {
+        for (name, store) in stores("deletes_threads_with_everything_in_them").await {
+            let thread = store
+                .insert_message(&message("Alice", "thread", None, 5))
+                .await
+                .unwrap();
+            let reply = store
+                .insert_message(&message("Bob", "reply", Some(thread), 4))
+                .await
+                .unwrap();
+            let kept = store
+                .insert_message(&message("Carol", "kept", None, 3))
+                .await
+                .unwrap();
+            store.toggle_reaction(reply, "alice", "🎉").await.unwrap();
+            store.toggle_reaction(kept, "alice", "🎉").await.unwrap();
+            store
+                .add_attachment(&attachment(thread, "a"))
+                .await
+                .unwrap();
+            store.add_attachment(&attachment(reply, "b")).await.unwrap();
+            store.add_attachment(&attachment(kept, "c")).await.unwrap();
+
+            let mut keys = store.delete_message(thread).await.unwrap();
+            keys.sort();
+            assert_eq!(keys, ["a", "b"], "{name}");
+            assert!(store.get_message(thread).await.unwrap().is_none(), "{name}");
+            assert!(store.get_message(reply).await.unwrap().is_none(), "{name}");
+            assert!(
+                store.get_reactions(reply).await.unwrap().is_empty(),
+                "{name}"
+            );
+            assert!(store.get_attachment("b").await.unwrap().is_none(), "{name}");
+            assert!(store.get_message(kept).await.unwrap().is_some(), "{name}");
+            assert_eq!(store.get_reactions(kept).await.unwrap().len(), 1, "{name}");
+            assert!(store.get_attachment("c").await.unwrap().is_some(), "{name}");
+            let found = store.search_messages(&search("reply"), 10).await.unwrap();
+            assert!(found.is_empty(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn searches_messages_users_can_see() {
+        for (name, store) in stores("searches_messages_users_can_see").await {
+            for (sender, source) in [("Alice", "the rocket launch"), ("Bob", "rocket science")] {
+                store
+                    .insert_message(&message(sender, source, None, 5))
+                    .await
+                    .unwrap();
+            }
+            store
+                .insert_message(&Message {
+                    recipient: Some("bob".to_string()),
+                    ..message("Alice", "secret rocket plans", None, 4)
+                })
+                .await
+                .unwrap();
+            store
+                .insert_message(&message("Alice", "!search rocket", None, 3))
+                .await
+                .unwrap();
+
+            let mut found = sources(&store.search_messages(&search("Rocket"), 10).await.unwrap())
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            found.sort();
+            assert_eq!(found, ["rocket science", "the rocket launch"], "{name}");
+            let by_alice = SearchFilter {
+                sender: Some("alice".to_string()),
+                ..search("rocket")
+            };
+            let found = store.search_messages(&by_alice, 10).await.unwrap();
+            assert_eq!(sources(&found), ["the rocket launch"], "{name}");
+            let secret = |viewer: &str| SearchFilter {
+                viewer: Some(viewer.to_string()),
+                ..search("secret")
+            };
+            let found = store.search_messages(&secret("bob"), 10).await.unwrap();
+            assert_eq!(sources(&found), ["secret rocket plans"], "{name}");
+            let found = store.search_messages(&secret("carol"), 10).await.unwrap();
+            assert!(found.is_empty(), "{name}");
+            let found = store.search_messages(&search("secret"), 10).await.unwrap();
+            assert!(found.is_empty(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn searches_replies_in_the_room_of_their_thread() {
+        for (name, store) in stores("searches_replies_in_the_room_of_their_thread").await {
+            let thread = store
+                .insert_message(&message("Alice", "launch day", None, 5))
+                .await
+                .unwrap();
+            store.add_to_room(1, thread).await.unwrap();
+            store
+                .insert_message(&message("Bob", "launch went well", Some(thread), 4))
+                .await
+                .unwrap();
+            store
+                .insert_message(&message("Carol", "launch elsewhere", None, 3))
+                .await
+                .unwrap();
+
+            let in_room = |room| SearchFilter {
+                room: Some(room),
+                ..search("went")
+            };
+            let found = store.search_messages(&in_room(1), 10).await.unwrap();
+            assert_eq!(sources(&found), ["launch went well"], "{name}");
+            let found = store.search_messages(&in_room(2), 10).await.unwrap();
+            assert!(found.is_empty(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn names_belong_to_the_first_session() {
+        for (name, store) in stores("names_belong_to_the_first_session").await {
+            assert!(store.claim_name("Alice", "one").await.unwrap(), "{name}");
+            assert!(store.claim_name("alice", "one").await.unwrap(), "{name}");
+            assert!(!store.claim_name("A lice", "two").await.unwrap(), "{name}");
+            assert!(store.claim_name("Bob", "two").await.unwrap(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn retention_keeps_pinned_threads() {
+        for (name, store) in stores("retention_keeps_pinned_threads").await {
+            let mut threads = vec![];
+            for source in ["pinned", "pinned reply", "old"] {
+                let message = message("Alice", source, None, 60);
+                threads.push(store.insert_message(&message).await.unwrap());
+            }
+            store.set_pinned(threads[0], true).await.unwrap();
+            let reply = store
+                .insert_message(&message("Bob", "answer", Some(threads[1]), 30))
+                .await
+                .unwrap();
+            store.set_pinned(reply, true).await.unwrap();
+            store
+                .insert_message(&message("Alice", "new", None, 0))
+                .await
+                .unwrap();
+
+            let policy = RetentionPolicy {
+                max_count: Some(1),
+                ..RetentionPolicy::default()
+            };
+            let candidates = store.retention_candidates(&policy).await.unwrap();
+            assert_eq!(sources(&candidates), ["old"], "{name}");
+        }
+    }
+
+    #[test]
+    fn migrations_are_numbered_in_step() {
+        let postgres = sqlx::migrate!("./migrations/postgres");
+        let sqlite = sqlx::migrate!("./migrations/sqlite");
+        let names = |migrator: &Migrator| {
+            migrator
+                .iter()
+                .map(|migration| (migration.version, migration.description.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let (postgres, sqlite) = (names(&postgres), names(&sqlite));
+        // The first SQLite migration makes the schema of every Postgres one up
+        // to it
+        let (first, _) = sqlite[0];
+        let later = postgres
+            .iter()
+            .filter(|(version, _)| *version > first)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(postgres.iter().any(|(version, _)| *version == first));
+        assert_eq!(sqlite[1..], later);
+    }
+
+    /// Check that both backends have the same tables and columns, which needs
+    /// a Postgres database at `DATABASE_URL`
+    #[tokio::test]
+    async fn backends_have_the_same_schema() {
+        let Some(postgres) = postgres_pool("test_backends_have_the_same_schema").await else {
+            return;
+        };
+        PostgresStore::new(postgres.clone()).await.unwrap();
+        let postgres_columns: Vec<(String, String)> = sqlx::query_as(
+            "SELECT table_name::TEXT, column_name::TEXT
+            FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name != '_sqlx_migrations'",
+        )
+        .fetch_all(&postgres)
+        .await
+        .unwrap();
+        let sqlite = sqlite_pool().await;
+        SqliteStore::new(sqlite.clone()).await.unwrap();
+        let sqlite_columns: Vec<(String, String)> = sqlx::query_as(
+            "SELECT tables.name, columns.name
+            FROM sqlite_master tables, pragma_table_info(tables.name) columns
+            WHERE tables.type = 'table' AND tables.name NOT GLOB '_*'
+                AND tables.name NOT GLOB 'sqlite_*' AND tables.name NOT GLOB 'messages_search*'",
+        )
+        .fetch_all(&sqlite)
+        .await
+        .unwrap();
+
+        // Postgres searches a column of messages where SQLite has a full text
+        // table, and orders attachments by a column where SQLite uses rowids
+        let postgres_only = [("messages", "search"), ("attachments", "position")];
+        let postgres_columns = postgres_columns
+            .iter()
+            .map(|(table, column)| (table.as_str(), column.as_str()))
+            .filter(|column| !postgres_only.contains(column))
+            .collect::<BTreeSet<_>>();
+        let sqlite_columns = sqlite_columns
+            .iter()
+            .map(|(table, column)| (table.as_str(), column.as_str()))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(postgres_columns, sqlite_columns);
+    }
+}
@@ -0,0 +1,81 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{migrate::MigrateError, PgPool};
+
+use super::{
+    decode_bot, encode_bot,
+    sql::{impl_chat_store, Dialect, IN_ROOM, MESSAGE_COLUMNS},
+    ChatStore, SearchFilter, MAX_DIRECT_MESSAGES,
+};
+use crate::{
+    ai::Bot,
+    models::{
+        user_key, AiUsage, Attachment, AuditEntry, LinkPreview, Message, Reaction, RetentionPolicy,
+        Review, Room, Sanction, SanctionKind, ScreenAction, ScreenPolicy, User,
+    },
+};
+
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    /// Use a database, running any migrations it is missing
+    pub async fn new(pool: PgPool) -> Result<Self, MigrateError> {
+        sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+const DIALECT: Dialect = Dialect {
+    date: str::to_string,
+    attachment_order: "position",
+};
+
+impl_chat_store!(PostgresStore, DIALECT, {
+    async fn get_reactions(&self, message: i32) -> sqlx::Result<Vec<Reaction>> {
+        sqlx::query_as(
+            "SELECT emoji, array_agg(username ORDER BY created) AS users
+        FROM reactions
+        WHERE message = $1
+        GROUP BY emoji
+        ORDER BY min(created)",
+        )
+        .bind(message)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn search_messages(
+        &self,
+        filter: &SearchFilter,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        sqlx::query_as(&format!(
+            "SELECT {MESSAGE_COLUMNS}
+        FROM messages
+        WHERE search @@ websearch_to_tsquery('english', $1)
+            AND ($2 IS NULL OR lower(replace(sender, ' ', '')) = $2)
+            AND ($3 IS NULL OR EXISTS (
+                SELECT 1 FROM room_messages
+                WHERE room_messages.message = coalesce(messages.parent_id, messages.id)
+                    AND room_messages.room = $3
+            ))
+            AND ($4 IS NULL OR sent_date >= $4)
+            AND ($5 IS NULL OR sent_date < $5)
+            AND (recipient IS NULL OR recipient = $6 OR lower(replace(sender, ' ', '')) = $6)
+            AND source NOT LIKE '!%'
+        ORDER BY ts_rank(search, websearch_to_tsquery('english', $1)) DESC, sent_date DESC
+        LIMIT $7"
+        ))
+        .bind(&filter.query)
+        .bind(&filter.sender)
+        .bind(filter.room)
+        .bind(filter.from)
+        .bind(filter.until)
+        .bind(&filter.viewer)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+});
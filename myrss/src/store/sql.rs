@@ -0,0 +1,626 @@
+//! The queries the Postgres and SQLite stores share. SQLite understands the
+//! `$1` parameters of Postgres and most of its SQL, so only the queries that
+//! can't be written for both, like full text search, are left to each
+//! backend. The rest differ in a few details, which each backend describes
+//! with a [`Dialect`].
+
+/// The columns of a [`Message`](crate::models::Message), in the order the
+/// store reads them
+pub const MESSAGE_COLUMNS: &str =
+    "id, parent_id, sender, recipient, sent_date, edited_at, contents, source, pinned, hidden,
+    session, (SELECT count(*) FROM messages replies WHERE replies.parent_id = messages.id) AS reply_count";
+
+/// Whether a message is in the room given by the first parameter, or in no
+/// room if that is null. Replies are in the room of their thread.
+pub const IN_ROOM: &str = "CASE WHEN $1 IS NULL
+    THEN NOT EXISTS (
+        SELECT 1 FROM room_messages
+        WHERE room_messages.message = coalesce(messages.parent_id, messages.id)
+    )
+    ELSE EXISTS (
+        SELECT 1 FROM room_messages
+        WHERE room_messages.message = coalesce(messages.parent_id, messages.id)
+            AND room_messages.room = $1
+    )
+END";
+
+/// How the SQL of a backend differs from the shared queries
+pub struct Dialect {
+    /// Turn a date column or parameter into something dates are compared and
+    /// ordered by
+    pub date: fn(&str) -> String,
+    /// The column that orders attachments the way they were added
+    pub attachment_order: &'static str,
+}
+
+/// Implement [`ChatStore`](super::ChatStore) for a store with a `pool`, with
+/// the shared queries and the methods given after the dialect. The methods
+/// are expanded in the backend's module, so they use the names imported
+/// there.
+macro_rules! impl_chat_store {
+    ($store:ty, $dialect:expr, { $($methods:tt)* }) => {
+        #[async_trait]
+        impl ChatStore for $store {
+            async fn insert_message(&self, message: &Message) -> sqlx::Result<i32> {
+                sqlx::query_scalar(
+                    "INSERT INTO messages
+                    (parent_id, sender, recipient, sent_date, contents, source, hidden, session)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                RETURNING id",
+                )
+                .bind(message.parent_id)
+                .bind(&message.sender)
+                .bind(&message.recipient)
+                .bind(message.sent_date)
+                .bind(&message.contents)
+                .bind(&message.source)
+                .bind(message.hidden)
+                .bind(&message.session)
+                .fetch_one(&self.pool)
+                .await
+            }
+
+            async fn get_message(&self, id: i32) -> sqlx::Result<Option<Message>> {
+                sqlx::query_as(&format!(
+                    "SELECT {MESSAGE_COLUMNS}
+                FROM messages
+                WHERE id = $1"
+                ))
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await
+            }
+
+            async fn get_replies(&self, id: i32) -> sqlx::Result<Vec<Message>> {
+                sqlx::query_as(&format!(
+                    "SELECT {MESSAGE_COLUMNS}
+                FROM messages
+                WHERE parent_id = $1
+                ORDER BY {sent_date}, id",
+                    sent_date = ($dialect.date)("sent_date")
+                ))
+                .bind(id)
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn get_threads(&self, room: Option<i32>) -> sqlx::Result<Vec<Message>> {
+                sqlx::query_as(&format!(
+                    "SELECT {MESSAGE_COLUMNS}
+                    FROM messages
+                    WHERE parent_id IS NULL AND recipient IS NULL AND {IN_ROOM}
+                    ORDER BY {sent_date}, id",
+                    sent_date = ($dialect.date)("sent_date")
+                ))
+                .bind(room)
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn latest_messages(&self, room: Option<i32>, limit: i64) -> sqlx::Result<Vec<Message>> {
+                let mut messages: Vec<Message> = sqlx::query_as(&format!(
+                    "SELECT {MESSAGE_COLUMNS}
+                    FROM messages
+                    WHERE recipient IS NULL AND {IN_ROOM}
+                    ORDER BY {sent_date} DESC, id DESC
+                    LIMIT $2",
+                    sent_date = ($dialect.date)("sent_date")
+                ))
+                .bind(room)
+                .bind(limit)
+                .fetch_all(&self.pool)
+                .await?;
+                messages.reverse();
+                Ok(messages)
+            }
+
+            async fn add_to_room(&self, room: i32, message: i32) -> sqlx::Result<()> {
+                sqlx::query("INSERT INTO room_messages (room, message) VALUES ($1, $2)")
+                    .bind(room)
+                    .bind(message)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn get_message_room(&self, message: i32) -> sqlx::Result<Option<Room>> {
+                sqlx::query_as(
+                    "SELECT rooms.id, rooms.name, rooms.description
+                    FROM rooms
+                    JOIN room_messages ON room_messages.room = rooms.id
+                    WHERE room_messages.message = $1",
+                )
+                .bind(message)
+                .fetch_optional(&self.pool)
+                .await
+            }
+
+            async fn get_direct_messages(&self, user: &str, other: &str) -> sqlx::Result<Vec<Message>> {
+                sqlx::query_as(&format!(
+                    "SELECT {MESSAGE_COLUMNS}
+                FROM messages
+                WHERE (lower(replace(sender, ' ', '')) = $1 AND recipient = $2)
+                    OR (lower(replace(sender, ' ', '')) = $2 AND recipient = $1)
+                ORDER BY {sent_date} DESC, id DESC
+                LIMIT $3",
+                    sent_date = ($dialect.date)("sent_date")
+                ))
+                .bind(user)
+                .bind(other)
+                .bind(MAX_DIRECT_MESSAGES)
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn count_replies(&self, id: i32) -> sqlx::Result<i64> {
+                sqlx::query_scalar("SELECT count(*) FROM messages WHERE parent_id = $1")
+                    .bind(id)
+                    .fetch_one(&self.pool)
+                    .await
+            }
+
+            async fn update_message(&self, message: &Message) -> sqlx::Result<()> {
+                sqlx::query(
+                    "UPDATE messages SET contents = $1, source = $2, edited_at = $3, hidden = $4
+                    WHERE id = $5",
+                )
+                .bind(&message.contents)
+                .bind(&message.source)
+                .bind(message.edited_at)
+                .bind(message.hidden)
+                .bind(message.id)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn delete_message(&self, id: i32) -> sqlx::Result<Vec<String>> {
+                let mut tx = self.pool.begin().await?;
+                let keys = sqlx::query_scalar(
+                    "SELECT key FROM attachments
+                    WHERE message = $1 OR message IN (SELECT id FROM messages WHERE parent_id = $1)",
+                )
+                .bind(id)
+                .fetch_all(&mut *tx)
+                .await?;
+                sqlx::query("DELETE FROM room_messages WHERE message = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM messages WHERE id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                Ok(keys)
+            }
+
+            async fn toggle_reaction(
+                &self,
+                message: i32,
+                user: &str,
+                emoji: &str,
+            ) -> sqlx::Result<Vec<Reaction>> {
+                let inserted = sqlx::query(
+                    "INSERT INTO reactions (message, username, emoji)
+                VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING",
+                )
+                .bind(message)
+                .bind(user)
+                .bind(emoji)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+                if inserted == 0 {
+                    sqlx::query(
+                        "DELETE FROM reactions WHERE message = $1 AND username = $2 AND emoji = $3",
+                    )
+                    .bind(message)
+                    .bind(user)
+                    .bind(emoji)
+                    .execute(&self.pool)
+                    .await?;
+                }
+                self.get_reactions(message).await
+            }
+
+            async fn set_pinned(&self, id: i32, pinned: bool) -> sqlx::Result<()> {
+                sqlx::query("UPDATE messages SET pinned = $1 WHERE id = $2")
+                    .bind(pinned)
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn retention_candidates(&self, policy: &RetentionPolicy) -> sqlx::Result<Vec<Message>> {
+                sqlx::query_as(&format!(
+                    "WITH threads AS (
+                        SELECT id, sent_date,
+                            row_number() OVER (ORDER BY {sent_date} DESC, id DESC) AS position
+                        FROM messages
+                        WHERE parent_id IS NULL
+                            AND NOT pinned
+                            AND NOT EXISTS (
+                                SELECT 1 FROM messages AS replies
+                                WHERE replies.parent_id = messages.id AND replies.pinned
+                            )
+                            AND {IN_ROOM}
+                    )
+                    SELECT {MESSAGE_COLUMNS}
+                    FROM messages
+                    WHERE id IN (
+                        SELECT id FROM threads
+                        WHERE {sent_date} < {cutoff} OR position > $3
+                    )
+                    ORDER BY {sent_date}, id",
+                    sent_date = ($dialect.date)("sent_date"),
+                    cutoff = ($dialect.date)("$2"),
+                ))
+                .bind(policy.room)
+                .bind(policy.cutoff())
+                .bind(policy.max_count)
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn add_attachment(&self, attachment: &Attachment) -> sqlx::Result<()> {
+                sqlx::query(
+                    "INSERT INTO attachments (key, message, filename, content_type, size, has_thumbnail)
+                    VALUES ($1, $2, $3, $4, $5, $6)",
+                )
+                .bind(&attachment.key)
+                .bind(attachment.message)
+                .bind(&attachment.filename)
+                .bind(&attachment.content_type)
+                .bind(attachment.size)
+                .bind(attachment.has_thumbnail)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn get_attachments(&self, message: i32) -> sqlx::Result<Vec<Attachment>> {
+                sqlx::query_as(&format!(
+                    "SELECT key, message, filename, content_type, size, has_thumbnail
+                    FROM attachments
+                    WHERE message = $1
+                    ORDER BY {}",
+                    $dialect.attachment_order
+                ))
+                .bind(message)
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn get_attachment(&self, key: &str) -> sqlx::Result<Option<Attachment>> {
+                sqlx::query_as(
+                    "SELECT key, message, filename, content_type, size, has_thumbnail
+                    FROM attachments
+                    WHERE key = $1",
+                )
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await
+            }
+
+            async fn get_link_preview(&self, url: &str) -> sqlx::Result<Option<LinkPreview>> {
+                sqlx::query_as(
+                    "SELECT url, title, description, site_name, fetched_at
+                    FROM link_previews
+                    WHERE url = $1",
+                )
+                .bind(url)
+                .fetch_optional(&self.pool)
+                .await
+            }
+
+            async fn save_link_preview(&self, preview: &LinkPreview) -> sqlx::Result<()> {
+                sqlx::query(
+                    "INSERT INTO link_previews (url, title, description, site_name, fetched_at)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (url) DO UPDATE SET
+                        title = excluded.title,
+                        description = excluded.description,
+                        site_name = excluded.site_name,
+                        fetched_at = excluded.fetched_at",
+                )
+                .bind(&preview.url)
+                .bind(&preview.title)
+                .bind(&preview.description)
+                .bind(&preview.site_name)
+                .bind(preview.fetched_at)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn active_sanctions(&self, user: Option<&str>) -> sqlx::Result<Vec<Sanction>> {
+                sqlx::query_as(&format!(
+                    "SELECT user_key, kind, reason, moderator, created_at, expires_at
+                    FROM sanctions
+                    WHERE ($1 IS NULL OR user_key = $1)
+                        AND (expires_at IS NULL OR {expires_at} > {now})
+                    ORDER BY user_key, kind",
+                    expires_at = ($dialect.date)("expires_at"),
+                    now = ($dialect.date)("$2"),
+                ))
+                .bind(user)
+                .bind(Utc::now())
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn add_sanction(&self, sanction: &Sanction) -> sqlx::Result<()> {
+                sqlx::query(
+                    "INSERT INTO sanctions (user_key, kind, reason, moderator, created_at, expires_at)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (user_key, kind) DO UPDATE SET
+                        reason = excluded.reason,
+                        moderator = excluded.moderator,
+                        created_at = excluded.created_at,
+                        expires_at = excluded.expires_at",
+                )
+                .bind(&sanction.user_key)
+                .bind(sanction.kind.as_str())
+                .bind(&sanction.reason)
+                .bind(&sanction.moderator)
+                .bind(sanction.created_at)
+                .bind(sanction.expires_at)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn remove_sanction(&self, user: &str, kind: SanctionKind) -> sqlx::Result<bool> {
+                let removed: Option<Option<DateTime<Utc>>> = sqlx::query_scalar(
+                    "DELETE FROM sanctions WHERE user_key = $1 AND kind = $2 RETURNING expires_at",
+                )
+                .bind(user)
+                .bind(kind.as_str())
+                .fetch_optional(&self.pool)
+                .await?;
+                Ok(removed
+                    .is_some_and(|expires_at| expires_at.is_none_or(|expires_at| expires_at > Utc::now())))
+            }
+
+            async fn log_moderation(&self, entry: &AuditEntry) -> sqlx::Result<()> {
+                sqlx::query(
+                    "INSERT INTO moderation_log (created_at, moderator, action, target, reason, expires_at)
+                    VALUES ($1, $2, $3, $4, $5, $6)",
+                )
+                .bind(entry.created_at)
+                .bind(&entry.moderator)
+                .bind(entry.action.as_str())
+                .bind(&entry.target)
+                .bind(&entry.reason)
+                .bind(entry.expires_at)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn moderation_log(&self, limit: i64) -> sqlx::Result<Vec<AuditEntry>> {
+                sqlx::query_as(
+                    "SELECT created_at, moderator, action, target, reason, expires_at
+                    FROM moderation_log
+                    ORDER BY id DESC
+                    LIMIT $1",
+                )
+                .bind(limit)
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn record_ai_usage(&self, usage: &AiUsage) -> sqlx::Result<()> {
+                sqlx::query(
+                    "INSERT INTO ai_usage (day, bot, user_key, prompt_tokens, completion_tokens, requests)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (day, bot, user_key) DO UPDATE SET
+                        prompt_tokens = ai_usage.prompt_tokens + excluded.prompt_tokens,
+                        completion_tokens = ai_usage.completion_tokens + excluded.completion_tokens,
+                        requests = ai_usage.requests + excluded.requests",
+                )
+                .bind(usage.day)
+                .bind(&usage.bot)
+                .bind(&usage.user_key)
+                .bind(usage.prompt_tokens)
+                .bind(usage.completion_tokens)
+                .bind(usage.requests)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn ai_usage(&self, since: NaiveDate, user: Option<&str>) -> sqlx::Result<Vec<AiUsage>> {
+                sqlx::query_as(
+                    "SELECT day, bot, user_key, prompt_tokens, completion_tokens, requests
+                    FROM ai_usage
+                    WHERE day >= $1 AND ($2 IS NULL OR user_key = $2)
+                    ORDER BY day DESC, bot, user_key",
+                )
+                .bind(since)
+                .bind(user)
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn rooms(&self) -> sqlx::Result<Vec<Room>> {
+                sqlx::query_as("SELECT id, name, description FROM rooms ORDER BY id")
+                    .fetch_all(&self.pool)
+                    .await
+            }
+
+            async fn retention_policies(&self) -> sqlx::Result<Vec<RetentionPolicy>> {
+                sqlx::query_as(
+                    "SELECT room, max_age_days, max_count
+                    FROM retention_policies
+                    ORDER BY room NULLS FIRST",
+                )
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn set_retention_policy(&self, policy: &RetentionPolicy) -> sqlx::Result<()> {
+                let mut tx = self.pool.begin().await?;
+                sqlx::query("DELETE FROM retention_policies WHERE room IS NOT DISTINCT FROM $1")
+                    .bind(policy.room)
+                    .execute(&mut *tx)
+                    .await?;
+                if !policy.is_unlimited() {
+                    sqlx::query(
+                        "INSERT INTO retention_policies (room, max_age_days, max_count)
+                        VALUES ($1, $2, $3)",
+                    )
+                    .bind(policy.room)
+                    .bind(policy.max_age_days)
+                    .bind(policy.max_count)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                tx.commit().await
+            }
+
+            async fn screen_policies(&self) -> sqlx::Result<Vec<ScreenPolicy>> {
+                sqlx::query_as("SELECT room, action FROM screen_policies ORDER BY room NULLS FIRST")
+                    .fetch_all(&self.pool)
+                    .await
+            }
+
+            async fn set_screen_policy(&self, policy: &ScreenPolicy) -> sqlx::Result<()> {
+                let mut tx = self.pool.begin().await?;
+                sqlx::query("DELETE FROM screen_policies WHERE room IS NOT DISTINCT FROM $1")
+                    .bind(policy.room)
+                    .execute(&mut *tx)
+                    .await?;
+                if policy.action != ScreenAction::default() {
+                    sqlx::query("INSERT INTO screen_policies (room, action) VALUES ($1, $2)")
+                        .bind(policy.room)
+                        .bind(policy.action.as_str())
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                tx.commit().await
+            }
+
+            async fn add_review(&self, review: &Review) -> sqlx::Result<i32> {
+                sqlx::query_scalar(
+                    "INSERT INTO reviews
+                        (message, action, reason, sender, parent_id, source, created_at, session)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                    RETURNING id",
+                )
+                .bind(review.message)
+                .bind(review.action.as_str())
+                .bind(&review.reason)
+                .bind(&review.sender)
+                .bind(review.parent_id)
+                .bind(&review.source)
+                .bind(review.created_at)
+                .bind(&review.session)
+                .fetch_one(&self.pool)
+                .await
+            }
+
+            async fn reviews(&self) -> sqlx::Result<Vec<Review>> {
+                sqlx::query_as(
+                    "SELECT id, message, action, reason, sender, parent_id, source, created_at, session
+                    FROM reviews
+                    ORDER BY id",
+                )
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            async fn get_review(&self, id: i32) -> sqlx::Result<Option<Review>> {
+                sqlx::query_as(
+                    "SELECT id, message, action, reason, sender, parent_id, source, created_at, session
+                    FROM reviews
+                    WHERE id = $1",
+                )
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await
+            }
+
+            async fn delete_review(&self, id: i32) -> sqlx::Result<()> {
+                sqlx::query("DELETE FROM reviews WHERE id = $1")
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            async fn record_user(&self, name: &str) -> sqlx::Result<()> {
+                sqlx::query(
+                    "INSERT INTO users (key, name, last_seen)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (key) DO UPDATE SET name = excluded.name, last_seen = excluded.last_seen",
+                )
+                .bind(user_key(name))
+                .bind(name)
+                .bind(Utc::now())
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn get_user(&self, key: &str) -> sqlx::Result<Option<User>> {
+                sqlx::query_as("SELECT key, name, last_seen FROM users WHERE key = $1")
+                    .bind(key)
+                    .fetch_optional(&self.pool)
+                    .await
+            }
+
+            async fn claim_name(&self, name: &str, session: &str) -> sqlx::Result<bool> {
+                let key = user_key(name);
+                sqlx::query(
+                    "INSERT INTO name_claims (key, session) VALUES ($1, $2)
+                    ON CONFLICT (key) DO NOTHING",
+                )
+                .bind(&key)
+                .bind(session)
+                .execute(&self.pool)
+                .await?;
+                let owner: String = sqlx::query_scalar("SELECT session FROM name_claims WHERE key = $1")
+                    .bind(&key)
+                    .fetch_one(&self.pool)
+                    .await?;
+                Ok(owner == session)
+            }
+
+            async fn bots(&self) -> sqlx::Result<Vec<Bot>> {
+                let bots: Vec<(String,)> = sqlx::query_as("SELECT data FROM bots ORDER BY id")
+                    .fetch_all(&self.pool)
+                    .await?;
+                bots.iter().map(|(data,)| decode_bot(data)).collect()
+            }
+
+            async fn save_bot(&self, bot: &Bot) -> sqlx::Result<()> {
+                sqlx::query(
+                    "INSERT INTO bots (name, data)
+                    VALUES ($1, $2)
+                    ON CONFLICT (name) DO UPDATE SET data = excluded.data",
+                )
+                .bind(bot.name())
+                .bind(encode_bot(bot)?)
+                .execute(&self.pool)
+                .await?;
+                Ok(())
+            }
+
+            async fn delete_bot(&self, name: &str) -> sqlx::Result<()> {
+                sqlx::query("DELETE FROM bots WHERE name = $1")
+                    .bind(name)
+                    .execute(&self.pool)
+                    .await?;
+                Ok(())
+            }
+
+            $($methods)*
+        }
+    };
+}
+
+pub(super) use impl_chat_store;
@@ -0,0 +1,115 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{migrate::MigrateError, SqlitePool};
+
+use super::{
+    decode_bot, encode_bot, query_words,
+    sql::{impl_chat_store, Dialect, IN_ROOM, MESSAGE_COLUMNS},
+    ChatStore, SearchFilter, MAX_DIRECT_MESSAGES,
+};
+use crate::{
+    ai::Bot,
+    models::{
+        user_key, AiUsage, Attachment, AuditEntry, LinkPreview, Message, Reaction, RetentionPolicy,
+        Review, Room, Sanction, SanctionKind, ScreenAction, ScreenPolicy, User,
+    },
+};
+
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Use a database, running any migrations it is missing
+    pub async fn new(pool: SqlitePool) -> Result<Self, MigrateError> {
+        sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+/// Dates are kept as text, which only compares right once they are turned
+/// into numbers, and attachments are ordered by rowid
+const DIALECT: Dialect = Dialect {
+    date: julianday,
+    attachment_order: "rowid",
+};
+
+fn julianday(date: &str) -> String {
+    format!("julianday({date})")
+}
+
+impl_chat_store!(SqliteStore, DIALECT, {
+    async fn get_reactions(&self, message: i32) -> sqlx::Result<Vec<Reaction>> {
+        // SQLite has no arrays, so the users come back as a JSON array. Ordering
+        // the rows before grouping them keeps the users in the order they reacted.
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT emoji, json_group_array(username) AS users
+        FROM (SELECT * FROM reactions WHERE message = $1 ORDER BY created)
+        GROUP BY emoji
+        ORDER BY min(created)",
+        )
+        .bind(message)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(emoji, users)| {
+                let users =
+                    serde_json::from_str(&users).map_err(|e| sqlx::Error::ColumnDecode {
+                        index: "users".to_string(),
+                        source: Box::new(e),
+                    })?;
+                Ok(Reaction { emoji, users })
+            })
+            .collect()
+    }
+
+    async fn search_messages(
+        &self,
+        filter: &SearchFilter,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let Some(query) = match_query(&filter.query) else {
+            return Ok(Vec::new());
+        };
+        sqlx::query_as(&format!(
+            "SELECT {MESSAGE_COLUMNS}
+        FROM messages
+        JOIN (
+            SELECT rowid AS match_id, rank AS match_rank
+            FROM messages_search
+            WHERE messages_search MATCH $1
+        ) ON match_id = messages.id
+        WHERE ($2 IS NULL OR lower(replace(sender, ' ', '')) = $2)
+            AND ($3 IS NULL OR EXISTS (
+                SELECT 1 FROM room_messages
+                WHERE room_messages.message = coalesce(messages.parent_id, messages.id)
+                    AND room_messages.room = $3
+            ))
+            AND ($4 IS NULL OR julianday(sent_date) >= julianday($4))
+            AND ($5 IS NULL OR julianday(sent_date) < julianday($5))
+            AND (recipient IS NULL OR recipient = $6 OR lower(replace(sender, ' ', '')) = $6)
+            AND source NOT LIKE '!%'
+        ORDER BY match_rank, julianday(sent_date) DESC
+        LIMIT $7"
+        ))
+        .bind(query)
+        .bind(&filter.sender)
+        .bind(filter.room)
+        .bind(filter.from)
+        .bind(filter.until)
+        .bind(&filter.viewer)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+});
+
+/// Turn a search into an FTS5 query matching messages with all of its words.
+/// Every word is quoted, so nothing the user types is read as query syntax.
+fn match_query(query: &str) -> Option<String> {
+    let words = query_words(query)
+        .into_iter()
+        .map(|word| format!("\"{word}\""))
+        .collect::<Vec<_>>();
+    (!words.is_empty()).then(|| words.join(" "))
+}
@@ -1,4 +1,4 @@
-use crate::models;
+use crate::{models, moderation, retention, usage};
 use askama::Template;
 use chrono::FixedOffset;
 
@@ -14,23 +14,195 @@ fn format_datetime(datetime: &chrono::DateTime<chrono::Utc>, tz: &i32) -> String
     }
 }
 
+/// The text of the link to a message's thread
+fn format_expiry(expires_at: &Option<chrono::DateTime<chrono::Utc>>) -> String {
+    moderation::format_expiry(*expires_at)
+}
+
+pub fn replies_label(count: &i64) -> String {
+    match count {
+        0 => "Reply".to_string(),
+        1 => "1 reply".to_string(),
+        _ => format!("{count} replies"),
+    }
+}
+
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct StartTemplate;
 
 #[derive(Template)]
-#[template(path = "message.html", escape = "none")]
+#[template(path = "message.html")]
 pub struct MessageTemplate {
     pub message: models::Message,
     pub tz: i32,
+    /// The name of the user the message is rendered for
+    pub viewer: String,
+    /// Whether the user the message is rendered for can edit or delete it
+    pub can_modify: bool,
+    pub admin: bool,
+}
+
+#[derive(Template)]
+#[template(path = "reactions.html")]
+pub struct ReactionsTemplate {
+    pub id: i32,
+    pub reactions: Vec<models::Reaction>,
+    /// The name of the user the reactions are rendered for
+    pub viewer: String,
+}
+
+#[derive(Template)]
+#[template(path = "previews.html")]
+pub struct PreviewsTemplate {
+    pub id: i32,
+    pub previews: Vec<models::LinkPreview>,
 }
 
 #[derive(Template)]
 #[template(path = "feed.html")]
 pub struct FeedTemplate;
 
+#[derive(Template)]
+#[template(path = "thread.html")]
+pub struct ThreadTemplate {
+    pub root: models::Message,
+    /// The replies in the thread, newest first
+    pub replies: Vec<models::Message>,
+    pub tz: i32,
+    /// The name of the user the thread is rendered for
+    pub viewer: String,
+    /// The session of the user the thread is rendered for
+    pub session: Option<String>,
+    pub admin: bool,
+}
+
+impl ThreadTemplate {
+    fn can_modify(&self, message: &models::Message) -> bool {
+        self.admin || message.is_sent_from(self.session.as_deref())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "direct-messages.html")]
+pub struct DirectMessagesTemplate {
+    /// The user the direct messages are with
+    pub user: String,
+    pub user_key: String,
+    /// When the user last connected, if they ever have
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    /// The direct messages, newest first
+    pub messages: Vec<models::Message>,
+    pub tz: i32,
+    /// The name of the user the messages are rendered for
+    pub viewer: String,
+    /// The session of the user the messages are rendered for
+    pub session: Option<String>,
+    pub admin: bool,
+}
+
+impl DirectMessagesTemplate {
+    fn can_modify(&self, message: &models::Message) -> bool {
+        self.admin || message.is_sent_from(self.session.as_deref())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "search.html")]
+pub struct SearchTemplate {
+    pub query: String,
+    pub sender: String,
+    /// The rooms to choose from to search in
+    pub rooms: Vec<models::Room>,
+    pub room: Option<i32>,
+    pub from: String,
+    pub to: String,
+    /// The matching messages with the search terms highlighted, or `None` if
+    /// nothing was searched for
+    pub results: Option<Vec<models::Message>>,
+    pub tz: i32,
+    /// The name of the user the results are rendered for
+    pub viewer: String,
+}
+
 #[derive(Template)]
 #[template(path = "banned-name.html")]
 pub struct BannedName {
     pub name: String,
+    /// Why the name isn't allowed
+    pub reason: String,
+}
+
+#[derive(Template)]
+#[template(path = "retention.html")]
+pub struct RetentionTemplate {
+    /// The name of every room with what its retention policy would delete
+    pub scopes: Vec<(String, retention::RetentionReport)>,
+    pub tz: i32,
+}
+
+#[derive(Template)]
+#[template(path = "review.html")]
+pub struct ReviewTemplate {
+    /// Whether messages are screened at all
+    pub screening: bool,
+    /// The review queue, oldest first
+    pub reviews: Vec<models::Review>,
+    /// The name of every room with its screening policy
+    pub scopes: Vec<(String, models::ScreenPolicy)>,
+    pub actions: [models::ScreenAction; 4],
+    pub tz: i32,
+}
+
+/// A standalone page with an exported room, see [`crate::archive`]
+#[derive(Template)]
+#[template(path = "archive.html")]
+pub struct ArchiveTemplate {
+    pub room: Option<i32>,
+    pub exported_at: chrono::DateTime<chrono::Utc>,
+    /// The first message of every thread with its replies
+    pub threads: Vec<(models::Message, Vec<models::Message>)>,
+    pub bots: Vec<crate::ai::Bot>,
+    /// The JSON Lines archive, with `<` escaped
+    pub data: String,
+}
+
+#[derive(Template)]
+#[template(path = "archive-admin.html")]
+pub struct ArchiveAdminTemplate {
+    pub rooms: Vec<models::Room>,
+    /// What the last import did, or why it failed
+    pub result: Option<Result<String, String>>,
+}
+
+#[derive(Template)]
+#[template(path = "banned.html")]
+pub struct Banned {
+    /// Why the user is banned and until when
+    pub message: String,
+}
+
+#[derive(Template)]
+#[template(path = "moderation.html")]
+pub struct ModerationTemplate {
+    pub sanctions: Vec<models::Sanction>,
+    /// The latest moderation actions, newest first
+    pub log: Vec<models::AuditEntry>,
+    pub tz: i32,
+}
+
+#[derive(Template)]
+#[template(path = "usage.html")]
+pub struct UsageTemplate {
+    /// The usage on every day with any, newest first
+    pub days: Vec<usage::UsageTotal>,
+    pub bots: Vec<usage::UsageTotal>,
+    pub users: Vec<usage::UsageTotal>,
+    pub quotas: std::sync::Arc<usage::Quotas>,
+}
+
+#[derive(Template)]
+#[template(path = "admin-sign-in.html")]
+pub struct AdminSignIn {
+    pub failed: bool,
 }
@@ -0,0 +1,363 @@
+//! The tools bots can call while answering a question, which give them
+//! access to the chat. Every tool only sees what is public, since the
+//! answers are posted publicly too.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_openai::types::{ChatCompletionTool, ChatCompletionToolType, FunctionObject};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::Sender;
+
+use crate::{
+    models::{user_key, ChatEvent, Message},
+    presence::Presence,
+    render,
+    screening::Screening,
+    store::{ChatStore, SearchFilter},
+};
+
+const MAX_SEARCH_RESULTS: i64 = 10;
+const DEFAULT_RECENT_MESSAGES: usize = 10;
+const MAX_RECENT_MESSAGES: usize = 30;
+/// How much of every message tools show bots
+const MAX_MESSAGE_CHARS: usize = 300;
+/// The latest a reminder can be scheduled for
+const MAX_REMINDER_MINUTES: u64 = 7 * 24 * 60;
+/// The most reminders a user can have waiting to be posted
+const MAX_PENDING_REMINDERS: usize = 5;
+
+/// The chat as seen from the conversation a bot was asked something in
+pub struct ChatTools {
+    pub store: Arc<dyn ChatStore>,
+    pub tx: Sender<ChatEvent>,
+    pub presence: Arc<Presence>,
+    pub screening: Arc<Screening>,
+    pub reminders: Arc<Reminders>,
+    /// The user who asked
+    pub user: String,
+    /// The thread the question was asked in, if any
+    pub thread: Option<i32>,
+}
+
+/// The tools offered to bots
+pub fn definitions() -> Vec<ChatCompletionTool> {
+    let tool = |name: &str, description: &str, parameters| ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            parameters: Some(parameters),
+            strict: None,
+        },
+    };
+    let no_parameters = json!({ "type": "object", "properties": {} });
+    vec![
+        tool(
+            "search_history",
+            "Search the public chat history for messages with some words, best matches first.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "The words to search for" },
+                },
+                "required": ["query"],
+            }),
+        ),
+        tool(
+            "online_users",
+            "Get the names of the users who are in the chat right now.",
+            no_parameters.clone(),
+        ),
+        tool(
+            "room_topic",
+            "Get the name and topic of the room the conversation is in.",
+            no_parameters,
+        ),
+        tool(
+            "recent_messages",
+            "Read the latest messages of the conversation, oldest first: the replies of the \
+            thread if the question was asked in one, otherwise the latest messages of the feed.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "count": {
+                        "type": "integer",
+                        "description": format!("How many messages to read, at most {MAX_RECENT_MESSAGES}"),
+                    },
+                },
+            }),
+        ),
+        tool(
+            "schedule_reminder",
+            "Remind the user who asked about something later, in the conversation the \
+            question was asked in.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "minutes": {
+                        "type": "integer",
+                        "description": format!("How many minutes from now, at most {MAX_REMINDER_MINUTES}"),
+                    },
+                    "text": { "type": "string", "description": "What to remind the user of" },
+                },
+                "required": ["minutes", "text"],
+            }),
+        ),
+    ]
+}
+
+#[derive(Deserialize)]
+struct SearchArgs {
+    query: String,
+}
+
+#[derive(Deserialize)]
+struct RecentMessagesArgs {
+    count: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ReminderArgs {
+    minutes: u64,
+    text: String,
+}
+
+impl ChatTools {
+    /// Call a tool for a bot, getting the result to show the bot. Errors are
+    /// shown to the bot too, so it can try again or answer without the tool.
+    pub async fn call(&self, bot: &str, name: &str, arguments: &str) -> String {
+        let result = match name {
+            "search_history" => match serde_json::from_str(arguments) {
+                Ok(args) => self.search_history(args).await,
+                Err(e) => Err(e.into()),
+            },
+            "online_users" => Ok(self.online_users()),
+            "room_topic" => self.room_topic().await,
+            "recent_messages" => match serde_json::from_str(arguments) {
+                Ok(args) => self.recent_messages(args).await,
+                Err(e) => Err(e.into()),
+            },
+            "schedule_reminder" => match serde_json::from_str(arguments) {
+                Ok(args) => self.schedule_reminder(bot, args).await,
+                Err(e) => Err(e.into()),
+            },
+            _ => Err(anyhow::anyhow!("There is no tool called {name}")),
+        };
+        let result = result.unwrap_or_else(|e| format!("Error: {e:#}"));
+        log::info!(
+            "{bot} called {name}({arguments}) for {}, getting {} characters",
+            self.user,
+            result.len()
+        );
+        result
+    }
+
+    async fn search_history(&self, args: SearchArgs) -> anyhow::Result<String> {
+        let filter = SearchFilter {
+            query: args.query,
+            sender: None,
+            room: None,
+            from: None,
+            until: None,
+            viewer: None,
+        };
+        let results = self
+            .store
+            .search_messages(&filter, MAX_SEARCH_RESULTS)
+            .await?;
+        if results.is_empty() {
+            return Ok("No messages found.".to_string());
+        }
+        Ok(format_messages(&results))
+    }
+
+    fn online_users(&self) -> String {
+        let online = self.presence.online();
+        if online.is_empty() {
+            return "Nobody is online.".to_string();
+        }
+        online.join(", ")
+    }
+
+    async fn room_topic(&self) -> anyhow::Result<String> {
+        let room = match self.thread {
+            Some(thread) => self.store.get_message_room(thread).await?,
+            None => None,
+        };
+        Ok(match room {
+            Some(room) => format!("The room is \"{}\": {}", room.name, room.description),
+            None => "The conversation is in the main feed, which has no topic.".to_string(),
+        })
+    }
+
+    async fn recent_messages(&self, args: RecentMessagesArgs) -> anyhow::Result<String> {
+        let count = args
+            .count
+            .unwrap_or(DEFAULT_RECENT_MESSAGES)
+            .clamp(1, MAX_RECENT_MESSAGES);
+        let messages = match self.thread {
+            Some(thread) => {
+                let root = self.store.get_message(thread).await?;
+                root.into_iter()
+                    .chain(self.store.get_replies(thread).await?)
+                    .collect::<Vec<_>>()
+            }
+            None => {
+                self.store
+                    .latest_messages(None, count.try_into().unwrap_or(i64::MAX))
+                    .await?
+            }
+        };
+        if messages.is_empty() {
+            return Ok("There are no messages yet.".to_string());
+        }
+        Ok(format_messages(
+            &messages[messages.len().saturating_sub(count)..],
+        ))
+    }
+
+    /// Schedule a reminder, which is posted as a reply from the bot that
+    /// mentions the user. Reminders are only kept in memory, so they are lost
+    /// if the server stops. Their text is screened like messages users send,
+    /// but since nobody would be there to review it, reminders the room's
+    /// policy would do anything with aren't scheduled.
+    async fn schedule_reminder(&self, bot: &str, args: ReminderArgs) -> anyhow::Result<String> {
+        if args.minutes == 0 || args.minutes > MAX_REMINDER_MINUTES {
+            anyhow::bail!("Reminders have to be between 1 and {MAX_REMINDER_MINUTES} minutes away");
+        }
+        let source = format!("Reminder for @{}: {}", user_key(&self.user), args.text);
+        if let Some((_, reason)) = self
+            .screening
+            .screen(self.store.as_ref(), self.thread, &source)
+            .await
+        {
+            anyhow::bail!("The reminder can't be posted in this conversation: {reason}");
+        }
+        let Some(pending) = self.reminders.reserve(&self.user) else {
+            anyhow::bail!(
+                "{} already has {MAX_PENDING_REMINDERS} reminders waiting to be posted",
+                self.user
+            );
+        };
+        let store = self.store.clone();
+        let tx = self.tx.clone();
+        let sender = format!("{bot} (Bot)");
+        let thread = self.thread;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(args.minutes * 60)).await;
+            drop(pending);
+            let mut message = Message {
+                id: None,
+                parent_id: thread,
+                sender,
+                recipient: None,
+                sent_date: Utc::now(),
+                edited_at: None,
+                contents: render::render_message(&source),
+                source,
+                pinned: false,
+                hidden: false,
+                session: None,
+                should_notify: true,
+                reply_count: 0,
+                reactions: vec![],
+                attachments: vec![],
+                previews: vec![],
+            };
+            match store.insert_message(&message).await {
+                Ok(id) => message.id = Some(id),
+                Err(e) => {
+                    log::error!("Failed to save reminder:\n{e}");
+                    return;
+                }
+            }
+            // Nobody listening just means nobody is online to be reminded
+            let _ = tx.send(ChatEvent::Message(message));
+            if let Some(thread) = thread {
+                if let Ok(count) = store.count_replies(thread).await {
+                    let _ = tx.send(ChatEvent::Replies {
+                        message: thread,
+                        count,
+                    });
+                }
+            }
+        });
+        Ok(match args.minutes {
+            1 => "The reminder will be posted in a minute.".to_string(),
+            minutes => format!("The reminder will be posted in {minutes} minutes."),
+        })
+    }
+}
+
+/// How many reminders every user has waiting to be posted, by their key
+#[derive(Default)]
+pub struct Reminders {
+    pending: Mutex<HashMap<String, usize>>,
+}
+
+impl Reminders {
+    /// Count a reminder for a user until the returned guard is dropped, unless
+    /// they already have as many as they can
+    fn reserve(self: &Arc<Self>, user: &str) -> Option<PendingReminder> {
+        let key = user_key(user);
+        let mut pending = self.pending.lock().unwrap();
+        let count = pending.entry(key.clone()).or_default();
+        if *count >= MAX_PENDING_REMINDERS {
+            return None;
+        }
+        *count += 1;
+        Some(PendingReminder {
+            reminders: self.clone(),
+            key,
+        })
+    }
+}
+
+/// A reminder that is counted against its user until it is dropped
+struct PendingReminder {
+    reminders: Arc<Reminders>,
+    key: String,
+}
+
+impl Drop for PendingReminder {
+    fn drop(&mut self) {
+        let mut pending = self.reminders.pending.lock().unwrap();
+        if let Some(count) = pending.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Show messages to a bot, one on each line
+fn format_messages(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .map(|message| {
+            let mut source = message
+                .source
+                .chars()
+                .take(MAX_MESSAGE_CHARS)
+                .collect::<String>();
+            if source.len() < message.source.len() {
+                source.push('…');
+            }
+            format!(
+                "[{}] {} ({}): {}",
+                message.id.unwrap_or_default(),
+                message.sender,
+                message.sent_date.format("%Y-%m-%d %H:%M UTC"),
+                source
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
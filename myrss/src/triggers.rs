@@ -0,0 +1,201 @@
+//! Bots answering messages without being asked with a command: when they are
+//! mentioned, when a message matches their pattern, or by chance in a room.
+//! Bot answers can trigger other bots, so messages from bots only trigger a
+//! few answers in a row, and bots wait a while between the answers they give
+//! on their own in a conversation.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{ai::Bot, render};
+
+/// The most answers bots give in a row, counting the first one, after which
+/// bot answers no longer trigger bots
+pub const MAX_BOT_CHAIN: u32 = 3;
+/// How long bots wait between the answers they give on their own, if they
+/// don't have a cooldown set
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+/// How big a compiled pattern can get, to keep patterns cheap to match
+const MAX_PATTERN_SIZE: usize = 64 * 1024;
+/// How many cooldowns are kept before the expired ones are dropped
+const MAX_COOLDOWNS: usize = 10_000;
+
+/// When a bot answers messages on its own
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Triggers {
+    /// Whether the bot answers messages that mention it
+    #[serde(default = "mention_default")]
+    pub mention: bool,
+    /// A pattern that makes the bot answer messages matching it, ignoring case
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// The chance of answering any message in a room
+    #[serde(default)]
+    pub chances: Vec<RoomChance>,
+    /// How long the bot waits between answers it gives on its own in a
+    /// conversation, or the default if not set
+    #[serde(default)]
+    pub cooldown_secs: Option<u64>,
+}
+
+fn mention_default() -> bool {
+    true
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        Self {
+            mention: mention_default(),
+            pattern: None,
+            chances: vec![],
+            cooldown_secs: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomChance {
+    /// The room, or `None` for the main feed
+    pub room: Option<i32>,
+    /// From 0 to 1
+    pub chance: f64,
+}
+
+impl Triggers {
+    /// Set the chance of answering in a room, where 0 removes it
+    pub fn set_chance(&mut self, room: Option<i32>, chance: f64) {
+        self.chances.retain(|c| c.room != room);
+        if chance > 0.0 {
+            self.chances.push(RoomChance { room, chance });
+        }
+    }
+
+    fn chance(&self, room: Option<i32>) -> f64 {
+        self.chances
+            .iter()
+            .find(|c| c.room == room)
+            .map_or(0.0, |c| c.chance)
+    }
+
+    fn cooldown(&self) -> Duration {
+        self.cooldown_secs
+            .map_or(DEFAULT_COOLDOWN, Duration::from_secs)
+    }
+
+    /// Describe the triggers for `!listbots`
+    pub fn describe(&self) -> String {
+        let mut parts = vec![];
+        if self.mention {
+            parts.push("mentions".to_string());
+        }
+        if let Some(pattern) = &self.pattern {
+            parts.push(format!("`{pattern}`"));
+        }
+        for chance in &self.chances {
+            let place = match chance.room {
+                Some(room) => format!("room {room}"),
+                None => "the main feed".to_string(),
+            };
+            parts.push(format!("{}% in {place}", chance.chance * 100.0));
+        }
+        if parts.is_empty() {
+            return "commands only".to_string();
+        }
+        parts.join(", ")
+    }
+}
+
+/// Compile a trigger pattern, checking that it isn't too big
+pub fn compile_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(MAX_PATTERN_SIZE)
+        .build()
+}
+
+/// A message that bots might answer
+pub struct Incoming<'a> {
+    pub sender: &'a str,
+    /// The rendered message, which mentions are found in
+    pub contents: &'a str,
+    pub source: &'a str,
+    /// The thread the message is in, or `None` for the main feed
+    pub thread: Option<i32>,
+    /// The room the message is in, or `None` for the main feed
+    pub room: Option<i32>,
+    /// Whether the message is an answer from a bot
+    pub from_bot: bool,
+}
+
+/// When bots last answered in each conversation on their own
+#[derive(Default)]
+pub struct Cooldowns(Mutex<HashMap<(String, Option<i32>), Instant>>);
+
+impl Cooldowns {
+    /// Get the bot that answers a message on its own, if any, and start its
+    /// cooldown. Mentioned bots come first, in the order they are mentioned.
+    /// Users mentioning a bot always get an answer, but everything else waits
+    /// for the cooldown.
+    pub fn triggered(&self, bots: &[Bot], message: &Incoming) -> Option<String> {
+        let mentions = render::mentions(message.contents);
+        let mentioned = |bot: &Bot| {
+            bot.triggers().mention
+                && mentions
+                    .iter()
+                    .any(|mention| render::mention_matches(mention, bot.name()))
+        };
+        let mut candidates = mentions
+            .iter()
+            .filter_map(|mention| {
+                bots.iter()
+                    .find(|bot| render::mention_matches(mention, bot.name()))
+            })
+            .filter(|bot| mentioned(bot))
+            .collect::<Vec<_>>();
+        candidates.extend(bots.iter().filter(|bot| !mentioned(bot)));
+
+        let now = Instant::now();
+        let mut last_answers = self.0.lock().unwrap();
+        if last_answers.len() > MAX_COOLDOWNS {
+            let bots_cooldown = |name: &str| {
+                bots.iter()
+                    .find(|bot| bot.name() == name)
+                    .map_or(DEFAULT_COOLDOWN, |bot| bot.triggers().cooldown())
+            };
+            last_answers.retain(|(bot, _), last| now.duration_since(*last) < bots_cooldown(bot));
+        }
+        for bot in candidates {
+            // Bots never answer themselves
+            if message.sender == format!("{} (Bot)", bot.name()) {
+                continue;
+            }
+            let triggers = bot.triggers();
+            let is_mentioned = mentioned(bot);
+            let matches = || {
+                triggers.pattern.as_deref().is_some_and(|pattern| {
+                    compile_pattern(pattern).is_ok_and(|pattern| pattern.is_match(message.source))
+                })
+            };
+            let lucky = || rand::random::<f64>() < triggers.chance(message.room);
+            if !(is_mentioned || matches() || lucky()) {
+                continue;
+            }
+            let key = (bot.name().to_string(), message.thread);
+            let cooling_down = last_answers
+                .get(&key)
+                .is_some_and(|last| now.duration_since(*last) < triggers.cooldown());
+            if cooling_down && (message.from_bot || !is_mentioned) {
+                continue;
+            }
+            last_answers.insert(key, now);
+            return Some(bot.name().to_string());
+        }
+        None
+    }
+}
@@ -0,0 +1,540 @@
+//! Previews of the links in messages. Pages are fetched in the background
+//! after a message is sent and the previews are pushed to the clients once
+//! they are ready. Only pages on public addresses are fetched, so that
+//! messages can't be used to reach the network the server is in.
+
+use std::{
+    net::IpAddr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use chrono::Utc;
+use regex::Regex;
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE, LOCATION},
+    redirect::Policy,
+};
+use scraper::{Html, Selector};
+use tokio::sync::broadcast::Sender;
+use url::{Host, Url};
+
+use crate::{
+    models::{user_key, ChatEvent, LinkPreview, Message},
+    store::ChatStore,
+};
+
+/// The most links in a message that get previews
+const MAX_LINKS: usize = 3;
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_MAX_BYTES: usize = 512 * 1024;
+/// How long a preview is used before the page is fetched again
+const CACHE_HOURS: i64 = 24;
+const MAX_REDIRECTS: usize = 3;
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 300;
+const USER_AGENT: &str = "myrss link previews";
+
+/// Matches a URL in text, along with any punctuation that follows it
+static URL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"https?://[^\s<>"'`]+"#).unwrap());
+static META: LazyLock<Selector> = LazyLock::new(|| Selector::parse("meta").unwrap());
+static TITLE: LazyLock<Selector> = LazyLock::new(|| Selector::parse("title").unwrap());
+
+pub struct UnfurlConfig {
+    /// How long fetching a page can take, including redirects
+    pub timeout: Duration,
+    /// How much of a page is read. The metadata is at the start of a page, so
+    /// the rest is never needed.
+    pub max_bytes: usize,
+    /// Whether pages on private addresses can be fetched, which is only meant
+    /// for trying out previews against a local server
+    pub allow_private: bool,
+}
+
+impl UnfurlConfig {
+    /// Read the configuration from `UNFURL_TIMEOUT_SECS`, `UNFURL_MAX_BYTES`
+    /// and `UNFURL_ALLOW_PRIVATE`
+    pub fn from_env() -> Self {
+        let timeout = std::env::var("UNFURL_TIMEOUT_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let max_bytes = std::env::var("UNFURL_MAX_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES);
+        Self {
+            timeout: Duration::from_secs(timeout),
+            max_bytes,
+            allow_private: std::env::var("UNFURL_ALLOW_PRIVATE").is_ok_and(|allow| allow == "1"),
+        }
+    }
+}
+
+/// Get the links in the Markdown source of a message that get previews
+pub fn links(source: &str) -> Vec<Url> {
+    let mut links: Vec<Url> = vec![];
+    for found in URL.find_iter(source) {
+        let Ok(url) = Url::parse(trim_link(found.as_str())) else {
+            continue;
+        };
+        if url.host().is_some() && !links.contains(&url) {
+            links.push(url);
+        }
+        if links.len() == MAX_LINKS {
+            break;
+        }
+    }
+    links
+}
+
+/// Drop the punctuation a link is followed by in the text. Closing brackets
+/// are kept if the link has the opening bracket, like in Wikipedia links.
+fn trim_link(link: &str) -> &str {
+    let mut link = link;
+    while let Some(last) = link.chars().last() {
+        let trim = match last {
+            '.' | ',' | ':' | ';' | '!' | '?' | '*' | '_' | '~' => true,
+            ')' => link.matches('(').count() < link.matches(')').count(),
+            ']' => link.matches('[').count() < link.matches(']').count(),
+            _ => false,
+        };
+        if !trim {
+            break;
+        }
+        link = &link[..link.len() - last.len_utf8()];
+    }
+    link
+}
+
+/// Get the previews of the links in a message that have already been
+/// fetched, even if they are old
+pub async fn cached(store: &dyn ChatStore, source: &str) -> sqlx::Result<Vec<LinkPreview>> {
+    let mut previews = vec![];
+    for link in links(source) {
+        if let Some(preview) = store.get_link_preview(link.as_str()).await? {
+            if !preview.is_empty() {
+                previews.push(preview);
+            }
+        }
+    }
+    Ok(previews)
+}
+
+/// Fetch the previews of the links in a message in the background, and send
+/// them to the clients if any had to be fetched
+pub fn spawn_unfurl(
+    store: Arc<dyn ChatStore>,
+    config: Arc<UnfurlConfig>,
+    tx: Sender<ChatEvent>,
+    message: &Message,
+) {
+    let Some(id) = message.id else {
+        return;
+    };
+    let links = links(&message.source);
+    if links.is_empty() {
+        return;
+    }
+    let participants = message
+        .recipient
+        .clone()
+        .map(|recipient| [user_key(&message.sender), recipient]);
+    tokio::spawn(async move {
+        let mut previews = vec![];
+        let mut fetched = false;
+        for link in links {
+            let cached = match store.get_link_preview(link.as_str()).await {
+                Ok(cached) => cached,
+                Err(e) => {
+                    log::error!("Failed to load link preview:\n{e}");
+                    None
+                }
+            };
+            let preview = match cached {
+                Some(preview)
+                    if Utc::now() - preview.fetched_at < chrono::Duration::hours(CACHE_HOURS) =>
+                {
+                    preview
+                }
+                _ => {
+                    let preview = fetch_preview(&config, link).await;
+                    if let Err(e) = store.save_link_preview(&preview).await {
+                        log::error!("Failed to save link preview:\n{e}");
+                    }
+                    fetched = true;
+                    preview
+                }
+            };
+            if !preview.is_empty() {
+                previews.push(preview);
+            }
+        }
+        if fetched {
+            // Nobody listening just means there are no clients to update
+            let _ = tx.send(ChatEvent::Previews {
+                message: id,
+                previews,
+                participants,
+            });
+        }
+    });
+}
+
+/// Fetch the page at a link and get its preview. Pages that can't be fetched
+/// get an empty preview.
+async fn fetch_preview(config: &UnfurlConfig, url: Url) -> LinkPreview {
+    let page = match tokio::time::timeout(config.timeout, fetch_page(config, url.clone())).await {
+        Ok(Ok(page)) => Some(page),
+        Ok(Err(e)) => {
+            log::info!("Failed to fetch a preview of {url}: {e:#}");
+            None
+        }
+        Err(_) => {
+            log::info!("Fetching a preview of {url} timed out");
+            None
+        }
+    };
+    let (title, description, site_name) = page.as_deref().map(parse_page).unwrap_or_default();
+    LinkPreview {
+        url: url.to_string(),
+        title,
+        description,
+        site_name,
+        fetched_at: Utc::now(),
+    }
+}
+
+/// Fetch the start of an HTML page, following redirects
+async fn fetch_page(config: &UnfurlConfig, mut url: Url) -> anyhow::Result<String> {
+    for _ in 0..=MAX_REDIRECTS {
+        let mut response = request(config, &url).await?;
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .context("Redirected without a location")?
+                .to_str()?;
+            url = url.join(location)?;
+            continue;
+        }
+        if !response.status().is_success() {
+            bail!("The server responded with {}", response.status());
+        }
+        let is_html = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| {
+                content_type.starts_with("text/html")
+                    || content_type.starts_with("application/xhtml+xml")
+            });
+        if !is_html {
+            bail!("Not an HTML page");
+        }
+        let mut page = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            let room = config.max_bytes - page.len();
+            page.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if page.len() == config.max_bytes {
+                break;
+            }
+        }
+        return Ok(String::from_utf8_lossy(&page).into_owned());
+    }
+    bail!("Redirected too many times")
+}
+
+/// Send a request to a URL, making sure it goes to a public address.
+/// Redirects aren't followed, so that every address is checked.
+async fn request(config: &UnfurlConfig, url: &Url) -> anyhow::Result<reqwest::Response> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Unsupported scheme {}", url.scheme());
+    }
+    let port = url.port_or_known_default().context("No port")?;
+    let client = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .no_proxy()
+        .user_agent(USER_AGENT);
+    let (ip, client) = match url.host().context("No host")? {
+        Host::Ipv4(ip) => (IpAddr::V4(ip), client),
+        Host::Ipv6(ip) => (IpAddr::V6(ip), client),
+        Host::Domain(domain) => {
+            let addr = tokio::net::lookup_host((domain, port))
+                .await?
+                .find(|addr| config.allow_private || is_public(addr.ip()))
+                .context("The host has no public address")?;
+            // The request goes to the address that was checked, so the host
+            // can't resolve to another address in between
+            (addr.ip(), client.resolve(domain, addr))
+        }
+    };
+    if !config.allow_private && !is_public(ip) {
+        bail!("{ip} is not a public address");
+    }
+    let response = client
+        .build()?
+        .get(url.clone())
+        .header(ACCEPT, "text/html,application/xhtml+xml")
+        .send()
+        .await?;
+    Ok(response)
+}
+
+/// Whether an address is on the public internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space used by carrier-grade NAT
+                || a == 100 && (64..128).contains(&b)
+                // Benchmarking
+                || a == 198 && (b == 18 || b == 19)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let [first, second, ..] = ip.segments();
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // Unique local
+                    || first & 0xfe00 == 0xfc00
+                    // Link local
+                    || first & 0xffc0 == 0xfe80
+                    // Documentation
+                    || first == 0x2001 && second == 0xdb8)
+            }
+        },
+    }
+}
+
+/// Get the title, description and site name of a page from its OpenGraph
+/// metadata, falling back to the other metadata pages commonly have
+fn parse_page(page: &str) -> (Option<String>, Option<String>, Option<String>) {
+    let document = Html::parse_document(page);
+    let meta = |names: &[&str], max_chars| {
+        names.iter().find_map(|name| {
+            document
+                .select(&META)
+                .find(|element| {
+                    let element = element.value();
+                    [element.attr("property"), element.attr("name")]
+                        .into_iter()
+                        .flatten()
+                        .any(|key| key.eq_ignore_ascii_case(name))
+                })
+                .and_then(|element| element.value().attr("content"))
+                .and_then(|content| clean_text(content, max_chars))
+        })
+    };
+    let title = meta(&["og:title", "twitter:title"], MAX_TITLE_CHARS).or_else(|| {
+        document
+            .select(&TITLE)
+            .next()
+            .and_then(|title| clean_text(&title.text().collect::<String>(), MAX_TITLE_CHARS))
+    });
+    let description = meta(
+        &["og:description", "twitter:description", "description"],
+        MAX_DESCRIPTION_CHARS,
+    );
+    let site_name = meta(&["og:site_name"], MAX_TITLE_CHARS);
+    (title, description, site_name)
+}
+
+/// Collapse the whitespace in text from a page and shorten it, or get `None`
+/// if there is no text
+fn clean_text(text: &str, max_chars: usize) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return None;
+    }
+    if text.chars().count() <= max_chars {
+        return Some(text);
+    }
+    let mut short = text.chars().take(max_chars - 1).collect::<String>();
+    short.push('…');
+    Some(short)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const PAGE: &str = r#"<html><head>
+        <title>Fallback title</title>
+        <meta property="og:title" content="  An   example page ">
+        <meta name="description" content="Plain description">
+        <meta property="og:description" content="What the page is about">
+        <meta property="og:site_name" content="Example">
+        </head><body>Hello</body></html>"#;
+
+    fn config(allow_private: bool) -> UnfurlConfig {
+        UnfurlConfig {
+            timeout: Duration::from_secs(5),
+            max_bytes: DEFAULT_MAX_BYTES,
+            allow_private,
+        }
+    }
+
+    fn html(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn redirect(location: &str) -> String {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\n\
+            Connection: close\r\n\r\n"
+        )
+    }
+
+    /// Serve the responses `respond` gives for the paths that are requested
+    /// on a local port, counting the requests
+    async fn serve(respond: fn(&str) -> String) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut head = vec![];
+                    let mut buf = [0; 1024];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match socket.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let head = String::from_utf8_lossy(&head);
+                    let path = head.split(' ').nth(1).unwrap_or("/");
+                    // The client hangs up early when it has read enough
+                    let _ = socket.write_all(respond(path).as_bytes()).await;
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    fn url(addr: SocketAddr, path: &str) -> Url {
+        Url::parse(&format!("http://{addr}{path}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn previews_open_graph_metadata() {
+        let (addr, _) = serve(|_| html(PAGE)).await;
+        let preview = fetch_preview(&config(true), url(addr, "/page")).await;
+        assert_eq!(preview.title.as_deref(), Some("An example page"));
+        assert_eq!(
+            preview.description.as_deref(),
+            Some("What the page is about")
+        );
+        assert_eq!(preview.site_name.as_deref(), Some("Example"));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_title_and_description() {
+        let (addr, _) = serve(|_| {
+            html(
+                r#"<html><head><title>Just a title</title>
+                <meta name="description" content="Just a description"></head></html>"#,
+            )
+        })
+        .await;
+        let preview = fetch_preview(&config(true), url(addr, "/")).await;
+        assert_eq!(preview.title.as_deref(), Some("Just a title"));
+        assert_eq!(preview.description.as_deref(), Some("Just a description"));
+        assert_eq!(preview.site_name, None);
+    }
+
+    #[tokio::test]
+    async fn reads_at_most_max_bytes() {
+        let (addr, _) = serve(|_| html(&format!("{PAGE}{}", "x".repeat(256 * 1024)))).await;
+        let config = UnfurlConfig {
+            max_bytes: 1000,
+            ..config(true)
+        };
+        let page = fetch_page(&config, url(addr, "/big")).await.unwrap();
+        assert_eq!(page.len(), 1000);
+        assert!(page.starts_with("<html>"));
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_up_to_the_limit() {
+        // `/hops/n` is `n` redirects away from the page
+        let (addr, _) = serve(|path| match path.strip_prefix("/hops/") {
+            Some("0") => html(PAGE),
+            Some(hops) => redirect(&format!("/hops/{}", hops.parse::<usize>().unwrap() - 1)),
+            None => html(""),
+        })
+        .await;
+        let config = config(true);
+        let path = format!("/hops/{MAX_REDIRECTS}");
+        assert!(fetch_page(&config, url(addr, &path)).await.is_ok());
+        let path = format!("/hops/{}", MAX_REDIRECTS + 1);
+        let error = fetch_page(&config, url(addr, &path)).await.unwrap_err();
+        assert_eq!(error.to_string(), "Redirected too many times");
+    }
+
+    #[tokio::test]
+    async fn never_requests_private_addresses() {
+        let (addr, requests) = serve(|_| html(PAGE)).await;
+        let error = fetch_page(&config(false), url(addr, "/page"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "127.0.0.1 is not a public address");
+
+        let localhost = Url::parse(&format!("http://localhost:{}/page", addr.port())).unwrap();
+        let preview = fetch_preview(&config(false), localhost).await;
+        assert!(preview.is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn tells_public_addresses_from_private_ones() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
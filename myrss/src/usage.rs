@@ -0,0 +1,169 @@
+//! Accounting for the tokens bots use, per bot, user and day, and the daily
+//! quotas on them. Days are in UTC.
+
+use async_openai::types::CompletionUsage;
+use chrono::{Days, NaiveDate, Utc};
+
+use crate::{
+    models::{user_key, AiUsage},
+    store::ChatStore,
+};
+
+/// How many days `!usage` and the usage page look back, including today
+pub const USAGE_DAYS: u64 = 30;
+
+pub struct Quotas {
+    /// The most tokens one user's questions can use in a day
+    pub per_user: Option<i64>,
+    /// The most tokens all bots can use in a day
+    pub total: Option<i64>,
+}
+
+impl Quotas {
+    /// Read the quotas from `AI_DAILY_TOKENS_PER_USER` and `AI_DAILY_TOKENS`.
+    /// Quotas that aren't set are unlimited.
+    pub fn from_env() -> Self {
+        let quota = |var| {
+            std::env::var(var)
+                .ok()
+                .and_then(|tokens| tokens.parse::<i64>().ok())
+        };
+        Self {
+            per_user: quota("AI_DAILY_TOKENS_PER_USER"),
+            total: quota("AI_DAILY_TOKENS"),
+        }
+    }
+}
+
+/// The first day of the period that usage is shown for
+pub fn usage_since() -> NaiveDate {
+    today() - Days::new(USAGE_DAYS - 1)
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+/// Record the tokens a bot used answering a user
+pub async fn record(
+    store: &dyn ChatStore,
+    bot: &str,
+    user: &str,
+    usage: &CompletionUsage,
+) -> sqlx::Result<()> {
+    store
+        .record_ai_usage(&AiUsage {
+            day: today(),
+            bot: bot.to_string(),
+            user_key: user_key(user),
+            prompt_tokens: usage.prompt_tokens.into(),
+            completion_tokens: usage.completion_tokens.into(),
+            requests: 1,
+        })
+        .await
+}
+
+/// Check the quotas before a user asks a bot something, getting why they
+/// can't if they have used up a quota. The tokens of a response are only
+/// known after it, so the last question of a day can go over the quota.
+pub async fn check_quota(
+    store: &dyn ChatStore,
+    quotas: &Quotas,
+    user: &str,
+) -> sqlx::Result<Option<String>> {
+    if let Some(quota) = quotas.per_user {
+        let used = total_tokens(&store.ai_usage(today(), Some(&user_key(user))).await?);
+        if used >= quota {
+            return Ok(Some(format!(
+                "You have used your {quota} tokens of questions to bots for today. The quota \
+                resets at midnight UTC."
+            )));
+        }
+    }
+    if let Some(quota) = quotas.total {
+        let used = total_tokens(&store.ai_usage(today(), None).await?);
+        if used >= quota {
+            return Ok(Some(
+                "The bots have used up their tokens for today. The quota resets at midnight UTC."
+                    .to_string(),
+            ));
+        }
+    }
+    Ok(None)
+}
+
+fn total_tokens(usage: &[AiUsage]) -> i64 {
+    usage.iter().map(AiUsage::total_tokens).sum()
+}
+
+/// The usage added up for one day, bot or user
+pub struct UsageTotal {
+    pub name: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub requests: i64,
+}
+
+impl UsageTotal {
+    pub fn total_tokens(&self) -> i64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Add up usage by a key, keeping the order the keys first appear in
+pub fn totals(usage: &[AiUsage], key: impl Fn(&AiUsage) -> String) -> Vec<UsageTotal> {
+    let mut totals: Vec<UsageTotal> = vec![];
+    for usage in usage {
+        let name = key(usage);
+        let index = match totals.iter().position(|total| total.name == name) {
+            Some(index) => index,
+            None => {
+                totals.push(UsageTotal {
+                    name,
+                    prompt_tokens: 0,
+                    completion_tokens: 0,
+                    requests: 0,
+                });
+                totals.len() - 1
+            }
+        };
+        let total = &mut totals[index];
+        total.prompt_tokens += usage.prompt_tokens;
+        total.completion_tokens += usage.completion_tokens;
+        total.requests += usage.requests;
+    }
+    totals
+}
+
+/// Describe a user's usage for `!usage`, given their usage since
+/// [`usage_since`]
+pub fn summary(usage: &[AiUsage], quotas: &Quotas) -> String {
+    let today = today();
+    let today_usage = usage
+        .iter()
+        .filter(|usage| usage.day == today)
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut lines = vec![format!(
+        "Today your questions to bots used {} tokens in {} responses.",
+        total_tokens(&today_usage),
+        today_usage.iter().map(|usage| usage.requests).sum::<i64>()
+    )];
+    if let Some(quota) = quotas.per_user {
+        let left = (quota - total_tokens(&today_usage)).max(0);
+        lines.push(format!(
+            "You have {left} of your {quota} daily tokens left."
+        ));
+    }
+    lines.push(format!(
+        "In the last {USAGE_DAYS} days they used {} tokens:",
+        total_tokens(usage)
+    ));
+    for total in totals(usage, |usage| usage.bot.clone()) {
+        lines.push(format!(
+            "- {}: {} prompt and {} completion tokens in {} responses",
+            total.name, total.prompt_tokens, total.completion_tokens, total.requests
+        ));
+    }
+    lines.join("\n")
+}